@@ -0,0 +1,402 @@
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::proxy::{ProxyPolicy, RouteRule};
+use crate::runtimes::Runtime;
+use crate::selftest::RequiredStore;
+use crate::trust::TrustStore;
+use crate::{Resolver, ServeArgs};
+
+/// Searched in order when no --config is given. Whichever is used must pass
+/// `open_trusted`, as its settings are acted on as root.
+const CONFIG_PATHS: &[&str] = &["mimikry.toml", "/etc/mimikry/mimikry.toml"];
+/// Priority of `[[assets]]` roots that do not set one. The built-in roots
+/// rank ~/Downloads 300, /media 200, $MIMIKRY_ASSET_DIR 100, --artifacts 0.
+const DEFAULT_ASSET_PRIORITY: i32 = 50;
+
+/// mimikry.toml, or one of its `[profile.NAME]` tables. Every key is
+/// optional; a profile's keys replace the top-level ones, and command line
+/// flags replace both.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    domains: Option<Vec<String>>,
+    artifacts: Option<PathBuf>,
+    assets: Option<Vec<AssetRoot>>,
+    #[serde(default)]
+    listen: Listen,
+    #[serde(default)]
+    trust: Trust,
+    #[serde(default)]
+    resolver: ResolverSettings,
+    #[serde(default)]
+    proxy: ProxySettings,
+    route: Option<Vec<RouteRule>>,
+    #[serde(default)]
+    profile: BTreeMap<String, Settings>,
+}
+
+/// An extra directory searched for artifacts. Higher priorities win when
+/// the same file name exists in several roots.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AssetRoot {
+    pub path: PathBuf,
+    #[serde(default = "default_asset_priority")]
+    pub priority: i32,
+}
+
+fn default_asset_priority() -> i32 {
+    DEFAULT_ASSET_PRIORITY
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct Listen {
+    http: Option<Vec<SocketAddr>>,
+    https: Option<Vec<SocketAddr>>,
+    dns: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct Trust {
+    /// false is --no-trust
+    enabled: Option<bool>,
+    store: Option<TrustStore>,
+    all_users: Option<bool>,
+    runtimes: Option<Vec<Runtime>>,
+    require: Option<Vec<RequiredStore>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ResolverSettings {
+    backend: Option<Resolver>,
+    target_ips: Option<Vec<IpAddr>>,
+    upstream: Option<SocketAddr>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct ProxySettings {
+    listen: Option<Vec<SocketAddr>>,
+    policy: Option<ProxyPolicy>,
+}
+
+impl Settings {
+    /// `self` (a profile) over `base`, key by key.
+    fn over(self, base: Settings) -> Settings {
+        Settings {
+            domains: self.domains.or(base.domains),
+            artifacts: self.artifacts.or(base.artifacts),
+            assets: self.assets.or(base.assets),
+            listen: Listen {
+                http: self.listen.http.or(base.listen.http),
+                https: self.listen.https.or(base.listen.https),
+                dns: self.listen.dns.or(base.listen.dns),
+            },
+            trust: Trust {
+                enabled: self.trust.enabled.or(base.trust.enabled),
+                store: self.trust.store.or(base.trust.store),
+                all_users: self.trust.all_users.or(base.trust.all_users),
+                runtimes: self.trust.runtimes.or(base.trust.runtimes),
+                require: self.trust.require.or(base.trust.require),
+            },
+            resolver: ResolverSettings {
+                backend: self.resolver.backend.or(base.resolver.backend),
+                target_ips: self.resolver.target_ips.or(base.resolver.target_ips),
+                upstream: self.resolver.upstream.or(base.resolver.upstream),
+            },
+            proxy: ProxySettings {
+                listen: self.proxy.listen.or(base.proxy.listen),
+                policy: self.proxy.policy.or(base.proxy.policy),
+            },
+            route: self.route.or(base.route),
+            profile: BTreeMap::new(),
+        }
+    }
+}
+
+/// Loads `--config` (or the first of CONFIG_PATHS that exists), picks
+/// `--profile`, and fills in every setting not given on the command line.
+/// `matches` are the parsed flags, to tell given flags from defaults. The
+/// result is then checked as a whole, wherever each setting came from.
+pub fn apply(args: &mut ServeArgs, matches: &ArgMatches) -> Result<()> {
+    let path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => CONFIG_PATHS.iter().map(PathBuf::from).find(|path| path.is_file()),
+    };
+    match path {
+        Some(path) => merge(args, matches, &path)?,
+        None if args.profile.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--profile needs a config file, but none of {} exists",
+                CONFIG_PATHS.join(", ")
+            ))
+        }
+        None => {}
+    }
+
+    // Config domains are normalized already, command line ones not yet
+    if let Some(domains) = &mut args.domains {
+        let mut list = crate::parse_domains(domains);
+        normalize_domains(&mut list, "domains")?;
+        *domains = list.join(",");
+    }
+    check_listeners(args)
+}
+
+/// Fills `args` from the config file at `path`.
+fn merge(args: &mut ServeArgs, matches: &ArgMatches, path: &Path) -> Result<()> {
+    let settings = load(path, args.profile.as_deref())?;
+    match &args.profile {
+        Some(profile) => println!("   Using {} (profile '{}')", path.display(), profile),
+        None => println!("   Using {}", path.display()),
+    }
+
+    let from_cli = |id: &str| matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable));
+    macro_rules! fill {
+        ($id:literal, $field:expr, $value:expr) => {
+            if let (false, Some(value)) = (from_cli($id), $value) {
+                $field = value;
+            }
+        };
+    }
+
+    if !from_cli("domains") && settings.domains.is_some() {
+        args.domains_config = Some(path.to_path_buf());
+    }
+    fill!("domains", args.domains, settings.domains.map(|d| Some(d.join(","))));
+    fill!("artifacts", args.artifacts, settings.artifacts);
+    fill!("http_listen", args.http_listen, settings.listen.http);
+    fill!("https_listen", args.https_listen, settings.listen.https);
+    fill!("dns_listen", args.dns_listen, settings.listen.dns.map(Some));
+    fill!("no_trust", args.no_trust, settings.trust.enabled.map(|enabled| !enabled));
+    fill!("trust_store", args.trust_store, settings.trust.store);
+    fill!("all_users", args.all_users, settings.trust.all_users);
+    fill!("trust_runtimes", args.trust_runtimes, settings.trust.runtimes);
+    fill!("require_trust", args.require_trust, settings.trust.require);
+    fill!("resolver", args.resolver, settings.resolver.backend);
+    fill!("target_ip", args.target_ip, settings.resolver.target_ips);
+    fill!("dns_upstream", args.dns_upstream, settings.resolver.upstream.map(Some));
+    fill!("proxy_listen", args.proxy_listen, settings.proxy.listen);
+    fill!("proxy_policy", args.proxy_policy, settings.proxy.policy.map(Some));
+
+    args.asset_roots = settings.assets.unwrap_or_default();
+    args.proxy_routes = settings.route.unwrap_or_default();
+    Ok(())
+}
+
+/// Re-reads `domains` from the config file at `path`, as `apply` would pick
+/// them, for a reload while serving.
+pub fn reload_domains(path: &Path, profile: Option<&str>) -> Result<Vec<String>> {
+    let settings = load(path, profile)?;
+    settings.domains.with_context(|| format!("{} no longer sets `domains`", path.display()))
+}
+
+/// Parses and validates the whole file, every profile included, so a typo
+/// in one profile is caught whichever is in use.
+fn load(path: &Path, profile: Option<&str>) -> Result<Settings> {
+    let mut text = String::new();
+    open_trusted(path)?
+        .read_to_string(&mut text)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // toml's errors already quote the offending line and column
+    let mut base: Settings = toml::from_str(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+    let mut profiles = std::mem::take(&mut base.profile);
+    validate(&mut base, &path.display().to_string())?;
+    for (name, settings) in profiles.iter_mut() {
+        let at = format!("{}, [profile.{}]", path.display(), name);
+        if !settings.profile.is_empty() {
+            return Err(anyhow::anyhow!("{}: profiles cannot define profiles of their own", at));
+        }
+        validate(settings, &at)?;
+    }
+
+    let Some(name) = profile else { return Ok(base) };
+    let selected = profiles.remove(name).with_context(|| {
+        let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        if known.is_empty() {
+            format!("Unknown profile '{}': {} defines no profiles", name, path.display())
+        } else {
+            format!("Unknown profile '{}': {} defines {}", name, path.display(), known.join(", "))
+        }
+    })?;
+    Ok(selected.over(base))
+}
+
+/// Opens a config file only if nobody but root or the invoking user could
+/// have written it: a `mimikry.toml` planted in the working directory would
+/// otherwise pick the domains, paths and trust stores root acts on.
+fn open_trusted(path: &Path) -> Result<File> {
+    let file = File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let meta = file.metadata().with_context(|| format!("Failed to stat {}", path.display()))?;
+
+    let invoker = crate::get_real_user().map(|user| user.uid());
+    if meta.uid() != 0 && Some(meta.uid()) != invoker {
+        return Err(anyhow::anyhow!(
+            "{} is owned by uid {}, neither root nor the invoking user; refusing to use it",
+            path.display(),
+            meta.uid()
+        ));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return Err(anyhow::anyhow!(
+            "{} is writable by group or others (mode {:o}); refusing to use it",
+            path.display(),
+            meta.mode() & 0o7777
+        ));
+    }
+    Ok(file)
+}
+
+/// Checks what serde cannot, and normalizes domain names as the command line
+/// does. `at` locates `settings` in error messages.
+fn validate(settings: &mut Settings, at: &str) -> Result<()> {
+    if let Some(domains) = &mut settings.domains {
+        if domains.is_empty() {
+            return Err(anyhow::anyhow!("{}: `domains` is empty", at));
+        }
+        normalize_domains(domains, &format!("{}: domains", at))?;
+    }
+
+    for (i, asset) in settings.assets.iter().flatten().enumerate() {
+        if !asset.path.is_absolute() {
+            return Err(anyhow::anyhow!("{}: assets[{}].path {:?} must be absolute", at, i, asset.path));
+        }
+    }
+    if let Some(artifacts) = &settings.artifacts {
+        if !artifacts.is_absolute() {
+            return Err(anyhow::anyhow!("{}: artifacts {:?} must be absolute", at, artifacts));
+        }
+    }
+
+    if let Some(target_ips) = &settings.resolver.target_ips {
+        if target_ips.is_empty() {
+            return Err(anyhow::anyhow!("{}: resolver.target_ips is empty", at));
+        }
+    }
+
+    for (i, rule) in settings.route.iter_mut().flatten().enumerate() {
+        if rule.domains.is_empty() {
+            return Err(anyhow::anyhow!("{}: route[{}].domains is empty", at, i));
+        }
+        normalize_domains(&mut rule.domains, &format!("{}: route[{}].domains", at, i))?;
+    }
+    Ok(())
+}
+
+/// No two kinds of listener may share an address. Checked on the merged
+/// settings, as the clash may be between the file and the command line.
+fn check_listeners(args: &ServeArgs) -> Result<()> {
+    let listeners = [
+        ("--http-listen (listen.http)", &args.http_listen),
+        ("--https-listen (listen.https)", &args.https_listen),
+        ("--proxy-listen (proxy.listen)", &args.proxy_listen),
+    ];
+    for (i, (name, addrs)) in listeners.iter().enumerate() {
+        for (other, other_addrs) in &listeners[i + 1..] {
+            if let Some(addr) = addrs.iter().find(|addr| other_addrs.contains(*addr)) {
+                return Err(anyhow::anyhow!("{} and {} both use {}", name, other, addr));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lowercases and strips trailing dots like `parse_domains`, rejecting
+/// anything that is not a host name or a leading `*.` wildcard.
+fn normalize_domains(domains: &mut [String], at: &str) -> Result<()> {
+    for (i, domain) in domains.iter_mut().enumerate() {
+        let normalized = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+        if let Err(reason) = check_domain(&normalized) {
+            return Err(anyhow::anyhow!("{}[{}] '{}' is not a valid domain: {}", at, i, domain, reason));
+        }
+        *domain = normalized;
+    }
+    Ok(())
+}
+
+fn check_domain(domain: &str) -> Result<(), &'static str> {
+    let name = domain.strip_prefix("*.").unwrap_or(domain);
+    if name.is_empty() {
+        return Err("empty name");
+    }
+    if name.len() > 253 {
+        return Err("longer than 253 characters");
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err("empty label");
+        }
+        if label.len() > 63 {
+            return Err("label longer than 63 characters");
+        }
+        if label.contains('*') {
+            return Err("wildcards are only allowed as a leading `*.`");
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("only letters, digits, `-` and `_` are allowed");
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err("labels cannot start or end with `-`");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_names_and_leading_wildcards() {
+        for domain in ["update.code.visualstudio.com", "*.vscode-cdn.net", "localhost", "_dmarc.example.com", "xn--bcher-kva.de"] {
+            assert_eq!(check_domain(domain), Ok(()), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_labels() {
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(50); 6].join(".");
+        let cases = [
+            ("", "empty name"),
+            ("*.", "empty name"),
+            ("a..b", "empty label"),
+            (".example.com", "empty label"),
+            (long_label.as_str(), "label longer than 63 characters"),
+            (long_name.as_str(), "longer than 253 characters"),
+            ("foo.*.example.com", "wildcards are only allowed as a leading `*.`"),
+            ("*example.com", "wildcards are only allowed as a leading `*.`"),
+            ("exa mple.com", "only letters, digits, `-` and `_` are allowed"),
+            ("example.com:443", "only letters, digits, `-` and `_` are allowed"),
+            ("-example.com", "labels cannot start or end with `-`"),
+            ("example-.com", "labels cannot start or end with `-`"),
+        ];
+        for (domain, reason) in cases {
+            assert_eq!(check_domain(domain), Err(reason), "{}", domain);
+        }
+    }
+
+    #[test]
+    fn normalizes_case_and_trailing_dots() {
+        let mut domains = vec!["Update.Code.VisualStudio.com.".to_string(), " *.VSCODE-CDN.net ".to_string()];
+        normalize_domains(&mut domains, "domains").unwrap();
+        assert_eq!(domains, ["update.code.visualstudio.com", "*.vscode-cdn.net"]);
+
+        let mut domains = vec!["ok.example.com".to_string(), "bad..example.com".to_string()];
+        let err = normalize_domains(&mut domains, "domains").unwrap_err();
+        assert!(err.to_string().starts_with("domains[1] 'bad..example.com'"), "{}", err);
+    }
+}
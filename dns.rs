@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+use hickory_proto::op::{Message, MessageType, ResponseCode};
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{RData, Record, RecordType};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+use crate::net;
+
+const ANSWER_TTL: u32 = 60;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_UDP_PACKET: usize = 4096;
+/// A TCP client silent for this long, or this slow to send a message, is
+/// dropped, so idle connections cannot pile up.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimal authoritative DNS responder for the faked domains, so machines on
+/// the LAN can be pointed at one mimikry box instead of editing their hosts
+/// files. Everything else is forwarded upstream, or refused with NXDOMAIN.
+pub struct DnsServer {
+    patterns: Vec<String>,
+    answers: Vec<IpAddr>,
+    upstream: Option<SocketAddr>,
+}
+
+impl DnsServer {
+    pub fn new(patterns: &[String], answers: Vec<IpAddr>, upstream: Option<SocketAddr>) -> Self {
+        DnsServer {
+            patterns: patterns.to_vec(),
+            answers,
+            upstream,
+        }
+    }
+
+    /// Binds UDP and TCP on `listen`. Done up front, as port 53 needs root.
+    pub fn bind(listen: SocketAddr) -> Result<(UdpSocket, TcpListener)> {
+        Ok((net::bind_udp(listen)?, net::bind_tcp(listen)?))
+    }
+
+    /// Serves both sockets until either fails.
+    pub async fn serve(self: Arc<Self>, (udp, tcp): (UdpSocket, TcpListener)) -> Result<()> {
+        tokio::try_join!(self.clone().serve_udp(udp), self.serve_tcp(tcp))?;
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> Result<()> {
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; MAX_UDP_PACKET];
+
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let query = buf[..len].to_vec();
+            let (server, socket) = (self.clone(), socket.clone());
+
+            tokio::spawn(async move {
+                if let Some(reply) = server.answer(&query, false).await {
+                    let _ = socket.send_to(&reply, peer).await;
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = net::accept(&listener).await;
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _ = server.handle_tcp(stream).await;
+            });
+        }
+    }
+
+    /// TCP carries each message behind a two byte length prefix.
+    async fn handle_tcp(&self, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let read = async {
+                let len = stream.read_u16().await?;
+                let mut query = vec![0u8; len as usize];
+                stream.read_exact(&mut query).await?;
+                Ok::<_, std::io::Error>(query)
+            };
+            let query = match tokio::time::timeout(TCP_IDLE_TIMEOUT, read).await {
+                Ok(Ok(query)) => query,
+                // Closed by the client, or idle too long
+                _ => return Ok(()),
+            };
+
+            let Some(reply) = self.answer(&query, true).await else { return Ok(()) };
+            let Ok(len) = u16::try_from(reply.len()) else { return Ok(()) };
+            stream.write_u16(len).await?;
+            stream.write_all(&reply).await?;
+        }
+    }
+
+    /// Builds the reply to a raw query, which came over TCP if `tcp`. None
+    /// means the query is dropped.
+    async fn answer(&self, raw: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        let request = Message::from_vec(raw).ok()?;
+        let query = request.queries().first()?.clone();
+        let name = query.name().to_utf8().trim_end_matches('.').to_ascii_lowercase();
+
+        if !crate::matches_domain(&self.patterns, &name) {
+            if let Some(upstream) = self.upstream {
+                // Over TCP too, as a TCP query is often the retry of a truncated UDP reply
+                let forwarded = if tcp { forward_tcp(raw, upstream).await } else { forward(raw, upstream).await };
+                match forwarded {
+                    Ok(reply) => return Some(reply),
+                    Err(e) => eprintln!("   Warning: DNS forward of '{}' to {} failed: {}", name, upstream, e),
+                }
+                return reply(&request, ResponseCode::ServFail, Vec::new());
+            }
+            return reply(&request, ResponseCode::NXDomain, Vec::new());
+        }
+
+        // Other record types get an empty NOERROR answer (NODATA)
+        let records = self
+            .answers
+            .iter()
+            .filter_map(|ip| match (ip, query.query_type()) {
+                (IpAddr::V4(v4), RecordType::A) => Some(RData::A(A(*v4))),
+                (IpAddr::V6(v6), RecordType::AAAA) => Some(RData::AAAA(AAAA(*v6))),
+                _ => None,
+            })
+            .map(|rdata| Record::from_rdata(query.name().clone(), ANSWER_TTL, rdata))
+            .collect();
+
+        reply(&request, ResponseCode::NoError, records)
+    }
+}
+
+fn reply(request: &Message, code: ResponseCode, answers: Vec<Record>) -> Option<Vec<u8>> {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_authoritative(code != ResponseCode::ServFail)
+        .set_response_code(code)
+        .add_queries(request.queries().to_vec())
+        .add_answers(answers);
+    response.to_vec().ok()
+}
+
+/// Relays a raw query to the upstream resolver over UDP.
+async fn forward(raw: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if upstream.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(upstream).await?;
+    socket.send(raw).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_PACKET];
+    let len = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await.context("timed out")??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Relays a raw query to the upstream resolver over TCP, for replies too
+/// large for UDP.
+async fn forward_tcp(raw: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        stream.write_u16(u16::try_from(raw.len()).context("query too large")?).await?;
+        stream.write_all(raw).await?;
+
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+        Ok::<_, anyhow::Error>(buf)
+    };
+    tokio::time::timeout(UPSTREAM_TIMEOUT, exchange).await.context("timed out")?
+}
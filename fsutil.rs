@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Owner, mode and extended attributes (SELinux label included) of a file,
+/// to carry over to whatever replaces it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    #[serde(default)]
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl FileMeta {
+    pub fn of(path: &Path) -> io::Result<FileMeta> {
+        let meta = fs::metadata(path)?;
+        let mut xattrs = Vec::new();
+        for attr in xattr::list(path)? {
+            // Names are ASCII in practice; anything else could not be journaled
+            let Some(name) = attr.to_str() else { continue };
+            if let Some(value) = xattr::get(path, &attr)? {
+                xattrs.push((name.to_string(), value));
+            }
+        }
+        Ok(FileMeta { uid: meta.uid(), gid: meta.gid(), mode: meta.mode() & 0o7777, xattrs })
+    }
+}
+
+/// Replaces `path` with `contents` in one rename, keeping its owner, mode
+/// and extended attributes, so readers never see a half written file.
+pub fn replace(path: &Path, contents: &[u8]) -> Result<()> {
+    let meta = FileMeta::of(path).with_context(|| format!("Failed to stat {:?}", path))?;
+    replace_with(path, contents, &meta)
+}
+
+/// Like `replace`, but with the owner, mode and attributes in `meta`, e.g.
+/// those a file had before mimikry changed it.
+pub fn replace_with(path: &Path, contents: &[u8], meta: &FileMeta) -> Result<()> {
+    let tmp_path = stage(path, meta, contents)?;
+    commit(path, &tmp_path)
+}
+
+/// Writes `contents` to a temp file next to `path` carrying `meta`, ready to
+/// be renamed over `path` by `commit`.
+pub fn stage(path: &Path, meta: &FileMeta, contents: &[u8]) -> Result<PathBuf> {
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("file");
+    let tmp_path = path.with_file_name(format!(".{}.mimikry-tmp", name));
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create {:?}", tmp_path))?;
+    file.write_all(contents)?;
+
+    std::os::unix::fs::fchown(&file, Some(meta.uid), Some(meta.gid))?;
+    file.set_permissions(fs::Permissions::from_mode(meta.mode))?;
+
+    for (attr, value) in &meta.xattrs {
+        if let Err(e) = xattr::set(&tmp_path, attr, value) {
+            eprintln!("   Warning: could not copy xattr {:?} to {:?}: {}", attr, path, e);
+        }
+    }
+
+    file.sync_all()?;
+    Ok(tmp_path)
+}
+
+/// Renames a staged `tmp_path` over `path`, durably.
+pub fn commit(path: &Path, tmp_path: &Path) -> Result<()> {
+    fs::rename(tmp_path, path).with_context(|| format!("Failed to replace {:?}", path))?;
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
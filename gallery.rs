@@ -0,0 +1,598 @@
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::update::load_json;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(3600);
+const UPDATED_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_PAGE_SIZE: usize = 500;
+/// The Marketplace's own limit; larger requests get pages of this size.
+const MAX_PAGE_SIZE: usize = 1000;
+
+// FilterType
+const FILTER_TAG: u64 = 1;
+const FILTER_EXTENSION_ID: u64 = 4;
+const FILTER_CATEGORY: u64 = 5;
+const FILTER_EXTENSION_NAME: u64 = 7;
+const FILTER_TARGET: u64 = 8;
+const FILTER_FEATURED: u64 = 9;
+const FILTER_SEARCH_TEXT: u64 = 10;
+const FILTER_EXCLUDE_WITH_FLAGS: u64 = 12;
+
+// QueryFlags
+const FLAG_INCLUDE_VERSIONS: u64 = 0x1;
+const FLAG_INCLUDE_FILES: u64 = 0x2;
+const FLAG_INCLUDE_CATEGORY_AND_TAGS: u64 = 0x4;
+const FLAG_INCLUDE_VERSION_PROPERTIES: u64 = 0x10;
+const FLAG_INCLUDE_INSTALLATION_TARGETS: u64 = 0x40;
+const FLAG_INCLUDE_ASSET_URI: u64 = 0x80;
+const FLAG_INCLUDE_STATISTICS: u64 = 0x100;
+const FLAG_INCLUDE_LATEST_VERSION_ONLY: u64 = 0x200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortBy {
+    NoneOrRelevance,
+    LastUpdatedDate,
+    Title,
+    PublisherName,
+    InstallCount,
+    PublishedDate,
+    AverageRating,
+    WeightedRating,
+}
+
+impl SortBy {
+    fn from_u64(value: u64) -> Self {
+        match value {
+            1 => SortBy::LastUpdatedDate,
+            2 => SortBy::Title,
+            3 => SortBy::PublisherName,
+            4 => SortBy::InstallCount,
+            5 => SortBy::PublishedDate,
+            6 => SortBy::AverageRating,
+            12 => SortBy::WeightedRating,
+            _ => SortBy::NoneOrRelevance,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Stats {
+    install: f64,
+    averagerating: f64,
+    weighted_rating: f64,
+}
+
+#[derive(Clone, Debug)]
+struct GalleryExtension {
+    identity: String,
+    stats: Stats,
+    /// Marketplace JSON with asset URIs rewritten to `/artifacts/...` paths.
+    json: Value,
+}
+
+/// In-memory view of `<artifacts>/extensions`, answering marketplace
+/// `extensionquery` requests the same way vscgallery's `VSCGallery` does.
+pub struct Gallery {
+    artifacts: PathBuf,
+    extensions: RwLock<Arc<HashMap<String, GalleryExtension>>>,
+}
+
+impl Gallery {
+    pub fn new(artifacts: PathBuf) -> Self {
+        Gallery {
+            artifacts,
+            extensions: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    pub fn reload(&self) {
+        let mut extensions = HashMap::new();
+        let extensions_dir = self.artifacts.join("extensions");
+
+        let Ok(dirs) = fs::read_dir(&extensions_dir) else {
+            eprintln!("   Warning: extensions artifact directory missing {:?}", extensions_dir);
+            return;
+        };
+
+        for extension_dir in dirs.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()) {
+            if let Some(extension) = self.load_extension(&extension_dir) {
+                extensions.insert(extension.identity.clone(), extension);
+            }
+        }
+
+        println!("   Loaded {} extensions", extensions.len());
+        *self.extensions.write().unwrap() = Arc::new(extensions);
+    }
+
+    /// Reloads hourly, and whenever vscsync touches `updated.json`.
+    pub fn spawn_reloader(self: &Arc<Self>) {
+        let gallery = self.clone();
+        thread::spawn(move || {
+            let updated_path = gallery.artifacts.join("updated.json");
+            let mut last_seen = modified(&updated_path);
+            let mut last_reload = SystemTime::now();
+
+            loop {
+                thread::sleep(UPDATED_POLL_INTERVAL);
+                let current = modified(&updated_path);
+                let stale = last_reload.elapsed().map(|e| e >= RELOAD_INTERVAL).unwrap_or(true);
+
+                if current != last_seen || stale {
+                    if current != last_seen {
+                        println!("   Detected updated.json change, updating extension gallery");
+                    }
+                    gallery.reload();
+                    last_seen = current;
+                    last_reload = SystemTime::now();
+                }
+            }
+        });
+    }
+
+    fn load_extension(&self, extension_dir: &Path) -> Option<GalleryExtension> {
+        let latest_path = extension_dir.join("latest.json");
+        let Some(latest) = load_json(&latest_path) else {
+            eprintln!("   Warning: tried to load invalid manifest json {:?}", latest_path);
+            return None;
+        };
+        let mut latest = self.process_loaded_extension(latest, extension_dir)?;
+
+        let latest_version = latest["versions"][0].clone();
+
+        // Find other versions
+        let mut others = Vec::new();
+        if let Ok(dirs) = fs::read_dir(extension_dir) {
+            for version_path in dirs.filter_map(|e| e.ok()).map(|e| e.path().join("extension.json")) {
+                if !version_path.is_file() { continue; }
+                let Some(vers) = load_json(&version_path).and_then(|v| self.process_loaded_extension(v, extension_dir)) else {
+                    eprintln!("   Warning: tried to load invalid version manifest json {:?}", version_path);
+                    continue;
+                };
+
+                // If this extension.json is actually the latest version, then ignore it
+                if vers["versions"][0] == latest_version { continue; }
+                others.push(vers["versions"][0].clone());
+            }
+        }
+
+        let versions = latest["versions"].as_array_mut()?;
+        versions.extend(others);
+        versions.sort_by(|a, b| {
+            compare_loose_versions(
+                b["version"].as_str().unwrap_or_default(),
+                a["version"].as_str().unwrap_or_default(),
+            )
+        });
+
+        let identity = latest["identity"].as_str()?.to_string();
+        let stats = extract_stats(&latest);
+        Some(GalleryExtension { identity, stats, json: latest })
+    }
+
+    /// Repoints asset URIs at the mirror. They are stored host-relative and
+    /// completed per request, so any spoofed domain can serve them.
+    fn process_loaded_extension(&self, mut extension: Value, extension_dir: &Path) -> Option<Value> {
+        extension["identity"].as_str()?;
+        let relative_dir = extension_dir.strip_prefix(&self.artifacts).ok()?.to_string_lossy().into_owned();
+
+        for version in extension["versions"].as_array_mut()? {
+            let number = version["version"].as_str()?.to_string();
+            let asset_uri = match version.get("targetPlatform").and_then(|t| t.as_str()) {
+                Some(target) => format!("/artifacts/{}/{}/{}", relative_dir, number, target),
+                None => format!("/artifacts/{}/{}", relative_dir, number),
+            };
+
+            version["assetUri"] = Value::String(asset_uri.clone());
+            version["fallbackAssetUri"] = Value::String(asset_uri.clone());
+            if let Some(files) = version.get_mut("files").and_then(|f| f.as_array_mut()) {
+                for asset in files.iter_mut().filter(|a| a.is_object()) {
+                    let asset_type = asset["assetType"].as_str().unwrap_or_default().to_string();
+                    asset["source"] = Value::String(format!("{}/{}", asset_uri, asset_type));
+                }
+            }
+        }
+
+        if extension["versions"].as_array()?.is_empty() {
+            return None;
+        }
+        Some(extension)
+    }
+
+    /// `POST /_apis/public/gallery/extensionquery`
+    pub fn query(&self, body: &Value, host: &str) -> Response {
+        let filter = &body["filters"][0];
+        let (Some(criteria), Some(flags)) = (filter["criteria"].as_array(), body["flags"].as_u64()) else {
+            eprintln!("   Warning: post missing critical components. Raw post {}", body);
+            return StatusCode::NOT_FOUND.into_response();
+        };
+
+        let mut sort_by = SortBy::from_u64(filter["sortBy"].as_u64().unwrap_or(0));
+        let mut descending = filter["sortOrder"].as_u64().unwrap_or(0) != 1;
+
+        // If no order specified, default to InstallCount (e.g. popular first)
+        if sort_by == SortBy::NoneOrRelevance {
+            sort_by = SortBy::InstallCount;
+            descending = true;
+        }
+
+        let extensions = self.extensions.read().unwrap().clone();
+        let mut result = apply_criteria(&extensions, criteria);
+        sort(&mut result, sort_by, descending);
+
+        let total = result.len();
+        // Both come from the client: pages past the end are empty, never an overflow
+        let page_size = filter["pageSize"]
+            .as_u64()
+            .map_or(DEFAULT_PAGE_SIZE, |n| usize::try_from(n).unwrap_or(usize::MAX))
+            .clamp(1, MAX_PAGE_SIZE);
+        let page_number = filter["pageNumber"].as_u64().map_or(1, |n| usize::try_from(n).unwrap_or(usize::MAX));
+
+        let page: Vec<Value> = result
+            .into_iter()
+            .skip(page_number.saturating_sub(1).saturating_mul(page_size))
+            .take(page_size)
+            .map(|ext| shape_extension(ext, flags, host))
+            .collect();
+
+        warp::reply::json(&json!({
+            "results": [{
+                "extensions": page,
+                "pagingToken": null,
+                "resultMetadata": [{
+                    "metadataType": "ResultCount",
+                    "metadataItems": [{ "name": "TotalCount", "count": total }],
+                }],
+            }],
+        }))
+        .into_response()
+    }
+}
+
+/// Criteria of the same filter type are OR'd, different types are AND'd, so
+/// e.g. a search text combined with a category narrows the search.
+fn apply_criteria<'a>(extensions: &'a HashMap<String, GalleryExtension>, criteria: &[Value]) -> Vec<&'a GalleryExtension> {
+    let mut by_type: HashMap<u64, Vec<String>> = HashMap::new();
+
+    for crit in criteria {
+        let (Some(filter_type), Some(value)) = (crit["filterType"].as_u64(), crit["value"].as_str()) else {
+            continue;
+        };
+        match filter_type {
+            FILTER_TAG | FILTER_EXTENSION_ID | FILTER_CATEGORY | FILTER_EXTENSION_NAME | FILTER_SEARCH_TEXT => {
+                by_type.entry(filter_type).or_default().push(value.to_lowercase());
+            }
+            // Ignore the product, typically Visual Studio Code
+            FILTER_TARGET => {}
+            // Typically this ignores Unpublished Flag (4096) extensions
+            FILTER_EXCLUDE_WITH_FLAGS => {}
+            FILTER_FEATURED => println!("   Not implemented filter type {} for {}", filter_type, value),
+            _ => eprintln!("   Warning: undefined filter type {}", crit),
+        }
+    }
+
+    let mut result: Vec<&GalleryExtension> = if by_type.is_empty() {
+        Vec::new()
+    } else {
+        extensions
+            .values()
+            .filter(|ext| by_type.iter().all(|(filter_type, values)| values.iter().any(|v| criterion_matches(ext, *filter_type, v))))
+            .collect()
+    };
+
+    // Handle popular / recommended
+    if result.is_empty() && criteria.len() <= 2 {
+        result = extensions
+            .values()
+            .filter(|ext| ext.json["recommended"].as_bool().unwrap_or(false))
+            .collect();
+    }
+
+    result
+}
+
+fn criterion_matches(ext: &GalleryExtension, filter_type: u64, value: &str) -> bool {
+    let field = |name: &str| ext.json[name].as_str().unwrap_or_default().to_lowercase();
+    let list_contains = |name: &str| {
+        ext.json[name]
+            .as_array()
+            .map(|items| items.iter().filter_map(|i| i.as_str()).any(|i| i.to_lowercase() == value))
+            .unwrap_or(false)
+    };
+
+    match filter_type {
+        FILTER_TAG => list_contains("tags"),
+        FILTER_CATEGORY => list_contains("categories"),
+        FILTER_EXTENSION_ID => field("extensionId") == value,
+        FILTER_EXTENSION_NAME => ext.identity.to_lowercase() == value,
+        // Search in extension name, display name and short description
+        FILTER_SEARCH_TEXT => {
+            ext.identity.to_lowercase().contains(value)
+                || field("displayName").contains(value)
+                || field("shortDescription").contains(value)
+        }
+        _ => false,
+    }
+}
+
+fn sort(result: &mut [&GalleryExtension], sort_by: SortBy, descending: bool) {
+    fn text(ext: &GalleryExtension, pointer: &str) -> String {
+        ext.json.pointer(pointer).and_then(|v| v.as_str()).unwrap_or_default().to_string()
+    }
+    fn by_number(a: f64, b: f64) -> Ordering {
+        a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+    }
+
+    let key = |a: &GalleryExtension, b: &GalleryExtension| match sort_by {
+        SortBy::PublisherName => text(a, "/publisher/publisherName").cmp(&text(b, "/publisher/publisherName")),
+        SortBy::InstallCount => by_number(a.stats.install, b.stats.install),
+        SortBy::AverageRating => by_number(a.stats.averagerating, b.stats.averagerating),
+        SortBy::WeightedRating => by_number(a.stats.weighted_rating, b.stats.weighted_rating),
+        SortBy::LastUpdatedDate => text(a, "/lastUpdated").cmp(&text(b, "/lastUpdated")),
+        SortBy::PublishedDate => text(a, "/publishedDate").cmp(&text(b, "/publishedDate")),
+        SortBy::Title | SortBy::NoneOrRelevance => text(a, "/displayName").cmp(&text(b, "/displayName")),
+    };
+
+    // Name-like keys read naturally A-Z, so "descending" flips for them (as in vscgallery)
+    let name_like = matches!(sort_by, SortBy::PublisherName | SortBy::Title | SortBy::NoneOrRelevance);
+    let ascending = descending == name_like;
+
+    result.sort_by(|a, b| if ascending { key(a, b) } else { key(b, a) });
+}
+
+/// Applies QueryFlags to one extension and completes its asset URIs.
+fn shape_extension(ext: &GalleryExtension, flags: u64, host: &str) -> Value {
+    let mut json = ext.json.clone();
+    let Some(obj) = json.as_object_mut() else { return json };
+
+    if flags & FLAG_INCLUDE_CATEGORY_AND_TAGS == 0 {
+        obj.remove("tags");
+        obj.remove("categories");
+    }
+    if flags & FLAG_INCLUDE_STATISTICS == 0 {
+        obj.remove("statistics");
+    }
+    if flags & FLAG_INCLUDE_INSTALLATION_TARGETS == 0 {
+        obj.remove("installationTargets");
+    }
+
+    if flags & (FLAG_INCLUDE_VERSIONS | FLAG_INCLUDE_LATEST_VERSION_ONLY) == 0 {
+        obj.insert("versions".to_string(), Value::Array(Vec::new()));
+        return json;
+    }
+
+    if let Some(Value::Array(versions)) = obj.get_mut("versions") {
+        if flags & FLAG_INCLUDE_LATEST_VERSION_ONLY != 0 {
+            // Versions are sorted newest first: keep the first per target platform
+            let mut seen = Vec::new();
+            versions.retain(|v| {
+                let target = v["targetPlatform"].clone();
+                if seen.contains(&target) {
+                    false
+                } else {
+                    seen.push(target);
+                    true
+                }
+            });
+        }
+
+        for version in versions.iter_mut().filter_map(|v| v.as_object_mut()) {
+            shape_version(version, flags, host);
+        }
+    }
+
+    json
+}
+
+fn shape_version(version: &mut Map<String, Value>, flags: u64, host: &str) {
+    let absolute = |v: &Value| Value::String(format!("https://{}{}", host, v.as_str().unwrap_or_default()));
+
+    if flags & FLAG_INCLUDE_VERSION_PROPERTIES == 0 {
+        version.remove("properties");
+    }
+
+    if flags & FLAG_INCLUDE_ASSET_URI == 0 {
+        version.remove("assetUri");
+        version.remove("fallbackAssetUri");
+    } else {
+        for key in ["assetUri", "fallbackAssetUri"] {
+            if let Some(uri) = version.get_mut(key) {
+                *uri = absolute(uri);
+            }
+        }
+    }
+
+    if flags & FLAG_INCLUDE_FILES == 0 {
+        version.remove("files");
+    } else if let Some(Value::Array(files)) = version.get_mut("files") {
+        for source in files.iter_mut().filter_map(|f| f.get_mut("source")) {
+            *source = absolute(source);
+        }
+    }
+}
+
+fn extract_stats(extension: &Value) -> Stats {
+    let mut stats = Stats::default();
+    let Some(statistics) = extension["statistics"].as_array() else {
+        println!("   Statistics are missing from extension {}, generating.", extension["identity"]);
+        return stats;
+    };
+
+    for statistic in statistics {
+        let value = statistic["value"].as_f64().unwrap_or(0.0);
+        match statistic["statisticName"].as_str() {
+            Some("install") => stats.install = value,
+            Some("averagerating") => stats.averagerating = value,
+            Some("weightedRating") => stats.weighted_rating = value,
+            _ => {}
+        }
+    }
+    stats
+}
+
+/// Approximates Python's `LooseVersion`: numeric parts compare numerically,
+/// anything else lexically, and numbers sort before text.
+fn compare_loose_versions(a: &str, b: &str) -> Ordering {
+    let parts = |s: &str| -> Vec<String> {
+        let mut out = Vec::new();
+        let mut current = String::new();
+        for c in s.chars() {
+            let boundary = !current.is_empty() && current.chars().next_back().unwrap().is_ascii_digit() != c.is_ascii_digit();
+            if c == '.' || c == '-' || boundary {
+                if !current.is_empty() {
+                    out.push(std::mem::take(&mut current));
+                }
+                if c == '.' || c == '-' { continue; }
+            }
+            current.push(c);
+        }
+        if !current.is_empty() {
+            out.push(current);
+        }
+        out
+    };
+
+    let (a, b) = (parts(a), parts(b));
+    for (x, y) in a.iter().zip(b.iter()) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(identity: &str, installs: f64, json: Value) -> (String, GalleryExtension) {
+        let stats = Stats { install: installs, ..Stats::default() };
+        (identity.to_string(), GalleryExtension { identity: identity.to_string(), stats, json })
+    }
+
+    fn gallery() -> HashMap<String, GalleryExtension> {
+        HashMap::from([
+            extension(
+                "ms-python.python",
+                100.0,
+                json!({ "displayName": "Python", "categories": ["Programming Languages", "Linters"] }),
+            ),
+            extension(
+                "ms-python.pylint",
+                10.0,
+                json!({ "displayName": "Pylint", "categories": ["Linters"], "recommended": true }),
+            ),
+            extension("rust-lang.rust-analyzer", 50.0, json!({ "displayName": "rust-analyzer", "categories": ["Programming Languages"] })),
+        ])
+    }
+
+    fn identities(result: &[&GalleryExtension]) -> Vec<String> {
+        let mut ids: Vec<String> = result.iter().map(|ext| ext.identity.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    fn criterion(filter_type: u64, value: &str) -> Value {
+        json!({ "filterType": filter_type, "value": value })
+    }
+
+    #[test]
+    fn loose_versions_compare_numbers_numerically() {
+        assert_eq!(compare_loose_versions("1.10.0", "1.9.0"), Ordering::Greater);
+        assert_eq!(compare_loose_versions("1.2", "1.2.1"), Ordering::Less);
+        assert_eq!(compare_loose_versions("2023.1.0", "2023.1.0"), Ordering::Equal);
+        assert_eq!(compare_loose_versions("1.0.0-beta", "1.0.0"), Ordering::Greater);
+        // Numbers sort before text
+        assert_eq!(compare_loose_versions("1.2.3", "1.2a"), Ordering::Less);
+    }
+
+    #[test]
+    fn criteria_of_one_type_are_ored() {
+        let extensions = gallery();
+        let criteria = [
+            criterion(FILTER_EXTENSION_NAME, "ms-python.python"),
+            criterion(FILTER_EXTENSION_NAME, "Rust-Lang.Rust-Analyzer"),
+        ];
+        let result = apply_criteria(&extensions, &criteria);
+        assert_eq!(identities(&result), ["ms-python.python", "rust-lang.rust-analyzer"]);
+    }
+
+    #[test]
+    fn criteria_of_different_types_are_anded() {
+        let extensions = gallery();
+        let criteria = [
+            criterion(FILTER_TARGET, "Microsoft.VisualStudio.Code"),
+            criterion(FILTER_SEARCH_TEXT, "py"),
+            criterion(FILTER_CATEGORY, "programming languages"),
+        ];
+        let result = apply_criteria(&extensions, &criteria);
+        assert_eq!(identities(&result), ["ms-python.python"]);
+    }
+
+    #[test]
+    fn no_match_falls_back_to_recommended() {
+        let extensions = gallery();
+        let result = apply_criteria(&extensions, &[criterion(FILTER_SEARCH_TEXT, "nothing like this")]);
+        assert_eq!(identities(&result), ["ms-python.pylint"]);
+    }
+
+    #[test]
+    fn sort_orders_counts_high_first_and_titles_a_to_z() {
+        let extensions = gallery();
+        let mut result: Vec<&GalleryExtension> = extensions.values().collect();
+
+        sort(&mut result, SortBy::InstallCount, true);
+        let order: Vec<&str> = result.iter().map(|ext| ext.identity.as_str()).collect();
+        assert_eq!(order, ["ms-python.python", "rust-lang.rust-analyzer", "ms-python.pylint"]);
+
+        // "Descending" reads A-Z for name-like keys, as in vscgallery
+        sort(&mut result, SortBy::Title, true);
+        let order: Vec<&str> = result.iter().map(|ext| ext.identity.as_str()).collect();
+        assert_eq!(order, ["ms-python.pylint", "ms-python.python", "rust-lang.rust-analyzer"]);
+    }
+
+    #[test]
+    fn latest_version_only_keeps_the_newest_per_platform() {
+        let (_, ext) = extension(
+            "ms-python.python",
+            0.0,
+            json!({
+                "versions": [
+                    { "version": "2.0.0", "targetPlatform": "linux-x64" },
+                    { "version": "2.0.0", "targetPlatform": "win32-x64" },
+                    { "version": "1.0.0", "targetPlatform": "linux-x64" },
+                ],
+            }),
+        );
+        let shaped = shape_extension(&ext, FLAG_INCLUDE_LATEST_VERSION_ONLY, "marketplace.visualstudio.com");
+        let versions: Vec<(&str, &str)> = shaped["versions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| (v["version"].as_str().unwrap(), v["targetPlatform"].as_str().unwrap()))
+            .collect();
+        assert_eq!(versions, [("2.0.0", "linux-x64"), ("2.0.0", "win32-x64")]);
+
+        let shaped = shape_extension(&ext, 0, "marketplace.visualstudio.com");
+        assert_eq!(shaped["versions"], json!([]));
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fsutil::{self, FileMeta};
+use crate::journal::{Journal, Mutation};
+use crate::STATE_DIR;
+
+const BACKUP_DIR: &str = "backups";
+const BACKUPS_KEPT: usize = 10;
+/// Attempts before giving up on a file another tool keeps rewriting.
+const WRITE_ATTEMPTS: usize = 3;
+
+/// Appends `lines` to the hosts file at `path`, journaling them first.
+pub fn append_lines(path: &Path, lines: &[String], journal: &Journal) -> Result<()> {
+    // Once, whatever the retries below: replay removes the lines either way
+    journal.record(Mutation::HostsLines { path: path.to_path_buf(), lines: lines.to_vec(), written: None })?;
+
+    let mut written = None;
+    edit(path, |current| {
+        let mut contents = current.to_string();
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        for line in lines {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+
+        written = Some(digest(&contents));
+        Ok(Some(contents))
+    })?;
+
+    journal.amend_last(|mutation| {
+        if let Mutation::HostsLines { written: recorded, .. } = mutation {
+            *recorded = written;
+        }
+    })
+}
+
+/// Drops every line matching `remove`. Returns true if the file changed.
+pub fn remove_lines(path: &Path, remove: impl Fn(&str) -> bool) -> Result<bool> {
+    let mut changed = false;
+    edit(path, |current| {
+        let kept: Vec<&str> = current.lines().filter(|line| !remove(line)).collect();
+        changed = kept.len() != current.lines().count();
+        if !changed {
+            return Ok(None);
+        }
+
+        let mut contents = kept.join("\n");
+        contents.push('\n');
+        Ok(Some(contents))
+    })?;
+    Ok(changed)
+}
+
+/// True if the file still holds exactly what mimikry last wrote.
+pub fn is_unchanged_since(path: &Path, written: &str) -> bool {
+    fs::read_to_string(path).map(|c| digest(&c) == written).unwrap_or(false)
+}
+
+/// Read-modify-write of `path`. `change` returns the new contents, or None to
+/// leave the file alone. If another tool rewrites the file while we work,
+/// the edit is redone on top of their version.
+fn edit(path: &Path, mut change: impl FnMut(&str) -> Result<Option<String>>) -> Result<()> {
+    for _ in 0..WRITE_ATTEMPTS {
+        let before = fs::metadata(path).with_context(|| format!("Failed to stat {:?}", path))?;
+        let current = fs::read_to_string(path)?;
+
+        let Some(contents) = change(&current)? else { return Ok(()) };
+
+        let meta = FileMeta::of(path).with_context(|| format!("Failed to stat {:?}", path))?;
+        let tmp_path = fsutil::stage(path, &meta, contents.as_bytes())?;
+
+        let now = fs::metadata(path)?;
+        if (now.ino(), now.mtime(), now.mtime_nsec(), now.len()) != (before.ino(), before.mtime(), before.mtime_nsec(), before.len()) {
+            eprintln!("   Warning: {:?} was modified concurrently, retrying", path);
+            let _ = fs::remove_file(&tmp_path);
+            continue;
+        }
+
+        backup(path, &current)?;
+        return fsutil::commit(path, &tmp_path);
+    }
+
+    Err(anyhow::anyhow!("{:?} keeps changing underneath us, giving up", path))
+}
+
+/// Keeps a timestamped copy of `contents` under /var/lib/mimikry/backups,
+/// pruning all but the newest few.
+fn backup(path: &Path, contents: &str) -> Result<()> {
+    let dir = Path::new(STATE_DIR).join(BACKUP_DIR);
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("hosts");
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    crate::certs::write_private(&dir.join(format!("{}.{}.bak", name, stamp)), contents)?;
+
+    let prefix = format!("{}.", name);
+    let mut backups: Vec<PathBuf> = fs::read_dir(&dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            let file_name = p.file_name().and_then(|s| s.to_str()).unwrap_or("");
+            file_name.starts_with(&prefix) && file_name.ends_with(".bak")
+        })
+        .collect();
+    // Millisecond stamps of equal width sort chronologically
+    backups.sort();
+    let excess = backups.len().saturating_sub(BACKUPS_KEPT);
+    for old in &backups[..excess] {
+        let _ = fs::remove_file(old);
+    }
+    Ok(())
+}
+
+fn digest(contents: &str) -> String {
+    format!("{:x}", Sha256::digest(contents.as_bytes()))
+}
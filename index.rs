@@ -0,0 +1,423 @@
+use anyhow::{Context, Result};
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+const MOUNT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often directories inotify could not watch are walked instead.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// Files modified more recently than this are left for the next rescan, as
+/// they may still be being written.
+const RESCAN_SETTLE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArtifactEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time in seconds since the epoch.
+    pub mtime: u64,
+    pub sha256: String,
+    /// Position of the owning search root. Lower wins when filenames collide.
+    pub priority: usize,
+}
+
+/// Filename -> artifact lookup table over all search roots.
+///
+/// Built once at startup (reusing hashes from the previous run when size and
+/// mtime still match), then kept current by inotify so new files are served
+/// without a restart. Trees inotify cannot watch are rescanned periodically.
+pub struct ArtifactIndex {
+    roots: Vec<PathBuf>,
+    db_path: PathBuf,
+    entries: RwLock<HashMap<String, Vec<ArtifactEntry>>>,
+}
+
+impl ArtifactIndex {
+    pub fn build(roots: Vec<PathBuf>, db_path: PathBuf) -> Result<Self> {
+        let cached = load_db(&db_path);
+        // inotify reports absolute paths, so roots must be absolute too
+        let roots = roots.into_iter().map(|r| fs::canonicalize(&r).unwrap_or(r)).collect();
+        let index = ArtifactIndex {
+            roots,
+            db_path,
+            entries: RwLock::new(HashMap::new()),
+        };
+
+        let (mut reused, mut hashed) = (0usize, 0usize);
+        for (priority, root) in index.roots.iter().enumerate() {
+            if !root.exists() { continue; }
+
+            for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+                if !entry.file_type().is_file() { continue; }
+                let path = entry.path();
+
+                let previous = cached.get(path).filter(|c| {
+                    stat(path).map(|(size, mtime)| c.size == size && c.mtime == mtime).unwrap_or(false)
+                });
+
+                let artifact = match previous {
+                    Some(c) => {
+                        reused += 1;
+                        ArtifactEntry { priority, ..c.clone() }
+                    }
+                    None => match describe(path, priority) {
+                        Ok(a) => {
+                            hashed += 1;
+                            a
+                        }
+                        Err(e) => {
+                            eprintln!("   Warning: could not index {:?}: {}", path, e);
+                            continue;
+                        }
+                    },
+                };
+                index.insert(artifact);
+            }
+        }
+
+        println!("   Indexed {} artifacts ({} cached, {} hashed)", reused + hashed, reused, hashed);
+
+        if let Err(e) = index.save() {
+            eprintln!("   Warning: could not persist artifact index: {}", e);
+        }
+
+        Ok(index)
+    }
+
+    pub fn lookup(&self, filename: &str) -> Option<ArtifactEntry> {
+        let entries = self.entries.read().unwrap();
+        entries.get(filename).and_then(|v| v.first()).cloned()
+    }
+
+    /// Looks up the entry for an exact path, e.g. a file under the artifacts root.
+    pub fn lookup_path(&self, path: &Path) -> Option<ArtifactEntry> {
+        let name = path.file_name()?.to_str()?;
+        let entries = self.entries.read().unwrap();
+        entries.get(name)?.iter().find(|e| e.path == path).cloned()
+    }
+
+    /// `lookup_path`, but only while the entry still matches the file's size
+    /// and mtime, so its hash describes the bytes on disk. Inotify events
+    /// arrive late, and never for trees that are only rescanned.
+    pub fn current_entry(&self, path: &Path) -> Option<ArtifactEntry> {
+        let entry = self.lookup_path(path)?;
+        let (size, mtime) = stat(path).ok()?;
+        (entry.size == size && entry.mtime == mtime).then_some(entry)
+    }
+
+    /// Writes the index through a temp file and a rename. The cache dir may
+    /// belong to the unprivileged user (see `privdrop::give_dir`) while this
+    /// still runs as root, so both go through an O_NOFOLLOW fd of the dir and
+    /// the temp file is created O_EXCL|O_NOFOLLOW: nothing planted there is
+    /// followed or written through.
+    pub fn save(&self) -> Result<()> {
+        let snapshot: Vec<ArtifactEntry> = {
+            let entries = self.entries.read().unwrap();
+            entries.values().flatten().cloned().collect()
+        };
+
+        let dir = self.db_path.parent().context("Index path has no parent directory")?;
+        fs::create_dir_all(dir)?;
+        let dir_fd = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+            .open(dir)
+            .with_context(|| format!("Failed to open {:?}", dir))?;
+        let name = c_name(&self.db_path)?;
+        let tmp_name = c_name(&self.db_path.with_extension("tmp"))?;
+
+        // SAFETY: valid fd and NUL-terminated names; the new fd is owned by `file`.
+        let mut file = unsafe {
+            // A leftover (or planted) temp entry is removed, never opened
+            libc::unlinkat(dir_fd.as_raw_fd(), tmp_name.as_ptr(), 0);
+            let fd = libc::openat(
+                dir_fd.as_raw_fd(),
+                tmp_name.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o644 as libc::c_uint,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).context("Failed to create temporary index");
+            }
+            File::from_raw_fd(fd)
+        };
+        file.write_all(&serde_json::to_vec(&snapshot)?)?;
+        file.sync_all()?;
+
+        // SAFETY: as above; renameat replaces a symlink at the target, never follows it.
+        if unsafe { libc::renameat(dir_fd.as_raw_fd(), tmp_name.as_ptr(), dir_fd.as_raw_fd(), name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to replace index");
+        }
+        Ok(())
+    }
+
+    /// Starts inotify watches on every existing root, plus a poller that picks
+    /// up filesystems (e.g. USB sticks) mounted below a root after startup.
+    /// The returned watcher must be kept alive for as long as updates are wanted.
+    pub fn watch(self: &Arc<Self>) -> Result<Arc<Mutex<RecommendedWatcher>>> {
+        let index = self.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            match res {
+                Ok(event) => index.handle_event(event),
+                Err(e) => eprintln!("   Warning: artifact watch error: {}", e),
+            }
+        })?;
+
+        // A recursive watch takes one inotify watch per directory, so a big
+        // tree can exhaust fs.inotify.max_user_watches. Such roots are
+        // rescanned periodically instead.
+        let mut unwatched = Vec::new();
+        for root in &self.roots {
+            if !root.exists() { continue; }
+            if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+                eprintln!(
+                    "   Warning: could not watch {:?} ({}), rescanning it every {}s instead",
+                    root,
+                    e,
+                    RESCAN_INTERVAL.as_secs()
+                );
+                unwatched.push(root.clone());
+            }
+        }
+
+        let watcher = Arc::new(Mutex::new(watcher));
+
+        let index = self.clone();
+        let mount_watcher = watcher.clone();
+        thread::spawn(move || {
+            let mut known = index.mounts_below_roots();
+            let mut last_rescan = Instant::now();
+            loop {
+                thread::sleep(MOUNT_POLL_INTERVAL);
+                let current = index.mounts_below_roots();
+
+                for mount in current.difference(&known) {
+                    println!("   New mount detected, indexing: {:?}", mount);
+                    index.refresh(mount);
+                    if let Err(e) = mount_watcher.lock().unwrap().watch(mount, RecursiveMode::Recursive) {
+                        eprintln!("   Warning: could not watch {:?} ({}), rescanning it instead", mount, e);
+                        unwatched.push(mount.clone());
+                    }
+                }
+                for mount in known.difference(&current) {
+                    println!("   Mount removed, dropping artifacts under: {:?}", mount);
+                    index.remove_path(mount);
+                    unwatched.retain(|dir| dir != mount);
+                }
+                known = current;
+
+                if !unwatched.is_empty() && last_rescan.elapsed() >= RESCAN_INTERVAL {
+                    for dir in &unwatched {
+                        index.rescan(dir);
+                    }
+                    last_rescan = Instant::now();
+                }
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    fn handle_event(&self, event: Event) {
+        match event.kind {
+            // Only index files once the writer has closed them, so half-copied
+            // installers are never served.
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Create(CreateKind::Folder)
+            | EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in &event.paths {
+                    self.refresh(path);
+                }
+            }
+            EventKind::Remove(_) => {
+                for path in &event.paths {
+                    self.remove_path(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Re-reads whatever is at `path`: a file is (re)hashed, a directory is
+    /// walked, and a path that no longer exists is dropped from the index.
+    fn refresh(&self, path: &Path) {
+        let Some(priority) = self.priority_for(path) else { return };
+
+        if path.is_dir() {
+            for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    self.upsert(entry.path(), priority);
+                }
+            }
+        } else if path.is_file() {
+            self.upsert(path, priority);
+        } else {
+            self.remove_path(path);
+        }
+    }
+
+    /// `refresh` for a directory nobody watches: only files whose size or
+    /// mtime changed are hashed again, and entries for vanished files dropped.
+    fn rescan(&self, dir: &Path) {
+        let mut present = HashSet::new();
+        for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() { continue; }
+            let path = entry.path();
+            present.insert(path.to_path_buf());
+
+            let Ok((size, mtime)) = stat(path) else { continue };
+            if let Some(known) = self.lookup_path(path) {
+                if known.size == size && known.mtime == mtime { continue; }
+            }
+            let settled = SystemTime::now()
+                .duration_since(UNIX_EPOCH + Duration::from_secs(mtime))
+                .is_ok_and(|age| age >= RESCAN_SETTLE);
+            if let (true, Some(priority)) = (settled, self.priority_for(path)) {
+                self.upsert(path, priority);
+            }
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|_, slot| {
+            slot.retain(|e| !e.path.starts_with(dir) || present.contains(&e.path));
+            !slot.is_empty()
+        });
+    }
+
+    fn upsert(&self, path: &Path, priority: usize) {
+        match describe(path, priority) {
+            Ok(artifact) => {
+                println!("   Indexed artifact: {:?}", artifact.path);
+                self.insert(artifact);
+            }
+            Err(e) => eprintln!("   Warning: could not index {:?}: {}", path, e),
+        }
+    }
+
+    fn insert(&self, artifact: ArtifactEntry) {
+        let Some(name) = artifact.path.file_name().and_then(|s| s.to_str()).map(str::to_string) else {
+            return;
+        };
+
+        let mut entries = self.entries.write().unwrap();
+        let slot = entries.entry(name).or_default();
+        slot.retain(|e| e.path != artifact.path);
+        slot.push(artifact);
+        slot.sort_by_key(|e| e.priority);
+    }
+
+    fn remove_path(&self, path: &Path) {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|_, slot| {
+            slot.retain(|e| !e.path.starts_with(path));
+            !slot.is_empty()
+        });
+    }
+
+    fn priority_for(&self, path: &Path) -> Option<usize> {
+        self.roots.iter().position(|root| path.starts_with(root))
+    }
+
+    fn mounts_below_roots(&self) -> HashSet<PathBuf> {
+        let Ok(mounts) = fs::read_to_string("/proc/self/mounts") else {
+            return HashSet::new();
+        };
+
+        mounts
+            .lines()
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(|field| PathBuf::from(unescape_mount_path(field)))
+            .filter(|mount| self.roots.iter().any(|root| mount.starts_with(root) && mount != root))
+            .collect()
+    }
+}
+
+fn load_db(db_path: &Path) -> HashMap<PathBuf, ArtifactEntry> {
+    // Like `save`, never through a symlink the cache dir's owner planted
+    let mut raw = Vec::new();
+    let read = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(db_path)
+        .and_then(|mut file| file.read_to_end(&mut raw));
+    if read.is_err() {
+        return HashMap::new();
+    }
+
+    match serde_json::from_slice::<Vec<ArtifactEntry>>(&raw) {
+        Ok(entries) => entries.into_iter().map(|e| (e.path.clone(), e)).collect(),
+        Err(e) => {
+            eprintln!("   Warning: ignoring unreadable artifact index {:?}: {}", db_path, e);
+            HashMap::new()
+        }
+    }
+}
+
+fn c_name(path: &Path) -> Result<CString> {
+    let name = path.file_name().context("Index path has no file name")?;
+    Ok(CString::new(name.as_bytes())?)
+}
+
+fn stat(path: &Path) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok((meta.len(), mtime))
+}
+
+fn describe(path: &Path, priority: usize) -> io::Result<ArtifactEntry> {
+    let (size, mtime) = stat(path)?;
+    Ok(ArtifactEntry {
+        path: path.to_path_buf(),
+        size,
+        mtime,
+        sha256: hash_file(path)?,
+        priority,
+    })
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// /proc/self/mounts encodes whitespace in paths as octal escapes (e.g. `\040`).
+fn unescape_mount_path(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let code = bytes[i + 1..i + 4].iter().fold(0u32, |acc, b| acc * 8 + u32::from(b - b'0'));
+            out.push(code as u8);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,336 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::certs;
+use crate::fsutil::{self, FileMeta};
+use crate::hosts;
+use crate::runtimes;
+use crate::trust;
+use crate::STATE_DIR;
+
+const JOURNAL_FILE: &str = "journal.json";
+const BACKUP_DIR: &str = "backups";
+const LOCK_FILE: &str = "lock";
+
+/// Exclusive `flock` on the state dir, held by whichever process may change
+/// the system or replay the journal. Without it a second instance would take
+/// a live server's journal for a crashed run and undo it.
+pub struct StateLock {
+    file: File,
+}
+
+impl StateLock {
+    /// Takes the lock, failing at once if another instance holds it.
+    pub fn acquire() -> Result<Self> {
+        let dir = Path::new(STATE_DIR);
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let path = dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))?;
+
+        // SAFETY: plain syscall on an fd we own.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(anyhow::anyhow!("Another mimikry instance is running (it holds {:?})", path));
+            }
+            return Err(err).with_context(|| format!("Failed to lock {:?}", path));
+        }
+        Ok(StateLock { file })
+    }
+
+    /// The locked fd. A child that inherits it shares the lock, which then
+    /// lasts until both have exited.
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// One change made to the system, recorded before it is made.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mutation {
+    /// Lines appended to a hosts-style file. `written` is the digest of the
+    /// file as mimikry left it, to spot edits by other tools.
+    HostsLines {
+        path: PathBuf,
+        lines: Vec<String>,
+        #[serde(default)]
+        written: Option<String>,
+    },
+    /// A file created or overwritten. `backup` holds the previous content and
+    /// `meta` its owner, mode and xattrs; `refresh` is run after restoring
+    /// (e.g. `update-ca-certificates --fresh`).
+    File {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+        #[serde(default)]
+        meta: Option<FileMeta>,
+        refresh: Option<Vec<String>>,
+    },
+    /// A CA anchored via p11-kit's `trust anchor`. `cert` is our copy of it,
+    /// which `trust anchor --remove` needs.
+    TrustAnchor { cert: PathBuf },
+    /// The CA imported into a Java keystore under mimikry's alias.
+    KeystoreEntry { keystore: PathBuf },
+    /// The CA appended to a PEM bundle (e.g. certifi) between marker lines.
+    BundleBlock { path: PathBuf },
+    /// A certificate added to an NSS DB. `backup` holds a certificate that was
+    /// under the same nickname before, re-added with `trust` on restore.
+    NssCert {
+        db: String,
+        nickname: String,
+        backup: Option<PathBuf>,
+        trust: String,
+    },
+    /// A directory created for one of the files above, removed once empty.
+    Dir { path: PathBuf },
+}
+
+/// Write-ahead log of system mutations in /var/lib/mimikry.
+///
+/// Every change is journaled (and fsynced) before it happens, so after a
+/// SIGKILL or power loss `mimikry cleanup` can put the system back exactly as
+/// it was. Undoing a mutation that never took effect is harmless.
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<Vec<Mutation>>,
+}
+
+impl Journal {
+    /// Opens the journal, keeping whatever a previous run failed to replay.
+    /// Callers must hold the StateLock.
+    pub fn open() -> Result<Self> {
+        let dir = Path::new(STATE_DIR);
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let path = dir.join(JOURNAL_FILE);
+        let entries = match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw).with_context(|| format!("Corrupt journal {:?}", path))?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Journal {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    /// NSS DBs the CA was imported into.
+    pub fn nss_databases(&self) -> Vec<String> {
+        let mut dbs: Vec<String> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|m| match m {
+                Mutation::NssCert { db, .. } => Some(db.clone()),
+                _ => None,
+            })
+            .collect();
+        dbs.sort();
+        dbs.dedup();
+        dbs
+    }
+
+    /// Persists `mutation`. Call this before touching the system.
+    pub fn record(&self, mutation: Mutation) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(mutation);
+        self.persist(&entries).context("Failed to write journal")
+    }
+
+    /// Changes the newest entry, for what is only known once the change is made.
+    pub fn amend_last(&self, amend: impl FnOnce(&mut Mutation)) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(last) = entries.last_mut() else { return Ok(()) };
+        amend(last);
+        self.persist(&entries).context("Failed to write journal")
+    }
+
+    /// Creates `dir` and its missing parents, journaling each so that cleanup
+    /// removes them again (if nothing else was put there meanwhile).
+    pub fn create_dir_all(&self, dir: &Path) -> Result<()> {
+        let missing: Vec<&Path> = dir.ancestors().take_while(|path| !path.exists()).collect();
+        for path in missing.into_iter().rev() {
+            self.record(Mutation::Dir { path: path.to_path_buf() })?;
+            fs::create_dir(path).with_context(|| format!("Failed to create {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// Journals that `path` is about to be created or overwritten, backing up
+    /// what is there now along with its metadata.
+    pub fn record_file(&self, path: &Path, refresh: Option<Vec<String>>) -> Result<()> {
+        let backup = self.backup(path)?;
+        let meta = match backup {
+            Some(_) => Some(FileMeta::of(path).with_context(|| format!("Failed to stat {:?}", path))?),
+            None => None,
+        };
+        self.record(Mutation::File { path: path.to_path_buf(), backup, meta, refresh })
+    }
+
+    /// Copies `path` (if it exists) into the backup dir and returns the copy.
+    fn backup(&self, path: &Path) -> Result<Option<PathBuf>> {
+        // Only a missing file is "nothing to restore": undo deletes the path then
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to back up {:?}", path)),
+        };
+        let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("file");
+        Ok(Some(self.store_backup(name, &contents)?))
+    }
+
+    /// Stores `contents` under a unique name in the backup dir.
+    pub fn store_backup(&self, name: &str, contents: impl AsRef<[u8]>) -> Result<PathBuf> {
+        let dir = Path::new(STATE_DIR).join(BACKUP_DIR);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let backup = dir.join(format!("{}.{}", name, stamp));
+        certs::write_private(&backup, contents)?;
+        Ok(backup)
+    }
+
+    /// Undoes every recorded mutation, newest first. Each entry is dropped from
+    /// the journal once undone, so an interrupted replay can simply be rerun.
+    pub fn replay(&self) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+
+        while let Some(mutation) = entries.last().cloned() {
+            undo(&mutation).with_context(|| format!("Failed to undo {:?}", mutation))?;
+            entries.pop();
+            self.persist(&entries)?;
+            discard_backup(&mutation);
+        }
+
+        let _ = fs::remove_file(&self.path);
+        Ok(())
+    }
+
+    fn persist(&self, entries: &[Mutation]) -> Result<()> {
+        certs::write_private(&self.path, &serde_json::to_string_pretty(entries)?)?;
+        // Make the rename itself durable
+        File::open(Path::new(STATE_DIR))?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn undo(mutation: &Mutation) -> Result<()> {
+    match mutation {
+        Mutation::HostsLines { path, lines, written } => {
+            if !path.exists() {
+                return Ok(());
+            }
+            if written.as_deref().is_some_and(|w| !hosts::is_unchanged_since(path, w)) {
+                eprintln!("   Warning: {:?} was changed by another tool; only removing mimikry's lines", path);
+            }
+            if hosts::remove_lines(path, |line| lines.iter().any(|l| l == line))? {
+                println!("   Removed {} lines from {:?}", lines.len(), path);
+            }
+        }
+        Mutation::File { path, backup, meta, refresh } => {
+            match backup {
+                Some(backup) => {
+                    // Never a copy: the backup is root-only, the original may not have been
+                    let contents = fs::read(backup)?;
+                    let meta = match meta {
+                        Some(meta) => meta.clone(),
+                        // Journaled by an older version: keep what the file has now
+                        None => FileMeta::of(path).unwrap_or(FileMeta { uid: 0, gid: 0, mode: 0o644, xattrs: Vec::new() }),
+                    };
+                    fsutil::replace_with(path, &contents, &meta).with_context(|| format!("Failed to restore {:?}", path))?;
+                    println!("   Restored {:?}", path);
+                }
+                None => {
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                        println!("   Removed {:?}", path);
+                    }
+                }
+            }
+
+            if let Some((program, args)) = refresh.as_ref().and_then(|r| r.split_first()) {
+                Command::new(program).args(args).output()?;
+            }
+        }
+        Mutation::TrustAnchor { cert } => {
+            // Fails if the anchor never made it in
+            if trust::remove_anchor(cert).is_ok() {
+                println!("   Removed p11-kit anchor");
+            }
+        }
+        Mutation::KeystoreEntry { keystore } => {
+            // Fails if the entry never made it in
+            if runtimes::remove_keystore_entry(keystore).is_ok() {
+                println!("   Removed CA from {:?}", keystore);
+            }
+        }
+        Mutation::BundleBlock { path } => {
+            if path.exists() {
+                runtimes::strip_bundle_block(path)?;
+                println!("   Removed CA from {:?}", path);
+            }
+        }
+        Mutation::NssCert { db, nickname, backup, trust: flags } => {
+            // Ignore errors if the cert never made it in
+            trust::certutil(db).args(["-D", "-n", nickname, "-d", db]).output().ok();
+
+            if let Some(backup) = backup {
+                // The backup lives in our private dir, so hand it over on stdin
+                let mut import = trust::certutil(db);
+                import.args(["-A", "-n", nickname, "-t", flags, "-d", db, "-a"]);
+                let out = trust::output_with_stdin(import, &fs::read(backup)?)?;
+                if !out.status.success() {
+                    return Err(anyhow::anyhow!("certutil failed: {}", String::from_utf8_lossy(&out.stderr)));
+                }
+            }
+            println!("   Restored NSS DB at {}", db);
+        }
+        Mutation::Dir { path } => match fs::remove_dir(path) {
+            Ok(()) => println!("   Removed {:?}", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) if e.raw_os_error() == Some(libc::ENOTEMPTY) => {
+                eprintln!("   Warning: leaving {:?} in place, it is no longer empty", path);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove {:?}", path)),
+        },
+    }
+    Ok(())
+}
+
+fn discard_backup(mutation: &Mutation) {
+    let backup = match mutation {
+        Mutation::File { backup, .. } | Mutation::NssCert { backup, .. } => backup,
+        Mutation::TrustAnchor { cert } => {
+            let _ = fs::remove_file(cert);
+            return;
+        }
+        Mutation::HostsLines { .. }
+        | Mutation::KeystoreEntry { .. }
+        | Mutation::BundleBlock { .. }
+        | Mutation::Dir { .. } => &None,
+    };
+    if let Some(backup) = backup {
+        let _ = fs::remove_file(backup);
+    }
+}
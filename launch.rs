@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use tempfile::TempDir;
+use tokio::process::{Child, Command};
+use tokio::signal;
+
+/// System CA bundles of the common distros (Debian, Fedora, Arch, Alpine, SUSE).
+pub const SYSTEM_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+    "/etc/ssl/ca-bundle.pem",
+];
+
+/// The CA as files a single command can be pointed at, whether or not it is
+/// in any trust store.
+pub struct CaEnv {
+    _dir: TempDir,
+    vars: Vec<(&'static str, PathBuf)>,
+}
+
+/// Writes the CA, plus a copy of the system bundle with the CA appended, to a
+/// private temp dir owned by the current user.
+pub fn ca_env(ca_pem: &str) -> Result<CaEnv> {
+    let dir = tempfile::Builder::new().prefix("mimikry-").tempdir()?;
+
+    let ca_file = dir.path().join("mimikry-ca.pem");
+    fs::write(&ca_file, ca_pem)?;
+
+    let bundle_file = dir.path().join("ca-bundle.pem");
+    let system = SYSTEM_BUNDLES.iter().find_map(|path| fs::read_to_string(path).ok());
+    fs::write(&bundle_file, with_ca(system.unwrap_or_default(), ca_pem))?;
+
+    let vars = vec![
+        // OpenSSL, Go, Ruby and everything built on them
+        ("SSL_CERT_FILE", bundle_file.clone()),
+        // Python requests, which ignores SSL_CERT_FILE in favour of certifi
+        ("REQUESTS_CA_BUNDLE", bundle_file),
+        // Node and Electron (VS Code) add this to their bundled roots
+        ("NODE_EXTRA_CA_CERTS", ca_file),
+    ];
+    Ok(CaEnv { _dir: dir, vars })
+}
+
+/// `bundle` with `ca_pem` appended.
+pub fn with_ca(mut bundle: String, ca_pem: &str) -> String {
+    if !bundle.is_empty() && !bundle.ends_with('\n') {
+        bundle.push('\n');
+    }
+    bundle.push_str(ca_pem);
+    bundle
+}
+
+/// Starts `command` with the CA variables (if any) and `extra_env`. The
+/// command is killed if mimikry dies first.
+pub fn spawn(command: &[String], ca: Option<&CaEnv>, extra_env: &[(&str, String)]) -> Result<Child> {
+    let (program, args) = command.split_first().context("No command given")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+
+    if let Some(ca) = ca {
+        cmd.envs(ca.vars.iter().map(|(k, v)| (k, v)));
+    }
+    cmd.envs(extra_env.iter().map(|(k, v)| (k, v)));
+
+    // SAFETY: prctl is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    cmd.spawn().with_context(|| format!("Failed to start {}", program))
+}
+
+/// Waits for `child` and returns its exit code. Ctrl+C reaches the command
+/// too, so on the first one it is left to exit by itself; a second kills it.
+pub async fn wait(child: &mut Child) -> Result<i32> {
+    tokio::select! {
+        status = child.wait() => return Ok(exit_code(status?)),
+        _ = signal::ctrl_c() => println!("\n>> Shutdown signal received, waiting for the command (Ctrl+C again to kill it)"),
+    }
+    tokio::select! {
+        status = child.wait() => Ok(exit_code(status?)),
+        _ = signal::ctrl_c() => {
+            child.kill().await?;
+            Ok(exit_code(child.wait().await?))
+        }
+    }
+}
+
+/// Exit code to pass on for a child, shell style for signals.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
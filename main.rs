@@ -1,311 +1,1073 @@
+mod certs;
+mod config;
+mod dns;
+mod fsutil;
+mod gallery;
+mod hosts;
+mod index;
+mod journal;
+mod launch;
+mod net;
+mod pac;
+mod privdrop;
+mod proxy;
+mod resolver;
+mod rootless;
+mod runtimes;
+mod selftest;
+mod serve;
+mod tls;
+mod trust;
+mod update;
+
 use anyhow::{Context, Result};
-use clap::Parser;
-use rcgen::{
-    BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType,
-};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use std::convert::Infallible;
 use std::env;
-use std::fs::{self, File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::net::{IpAddr, SocketAddr};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use futures::future;
+use notify::RecommendedWatcher;
+use rustls::ServerConfig;
+use tokio::net::TcpListener;
+use tokio::process::Child;
 use tokio::signal;
-use walkdir::WalkDir;
+use tokio::task::{JoinError, JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use users::os::unix::UserExt;
+use users::User;
+use warp::http::{Method, Request, Response};
+use warp::hyper::service::Service;
+use warp::hyper::Body;
 use warp::Filter;
 
+use config::AssetRoot;
+use gallery::Gallery;
+use index::ArtifactIndex;
+use pac::ProxyEndpoint;
+use journal::{Journal, StateLock};
+use proxy::{ProxyPolicy, RouteRule};
+use serve::Conditionals;
+use runtimes::Runtime;
+use selftest::RequiredStore;
+use trust::{TrustOptions, TrustStore};
+
 const MIMIKRY_TAG: &str = "#mimikry-entry";
-const CA_CERT_FILENAME: &str = "mimikry-ca.crt";
-const SYSTEM_CERT_DIR: &str = "/usr/local/share/ca-certificates";
-const NSS_DB_DIR: &str = ".pki/nssdb";
+const HOSTS_PATH: &str = "/etc/hosts";
+const STATE_DIR: &str = "/var/lib/mimikry";
+/// Written by the unprivileged server, so kept apart from the root-only state.
+const CACHE_DIR: &str = "/var/cache/mimikry";
+const INDEX_FILENAME: &str = "index.json";
+/// The `[::]` halves are skipped on hosts without IPv6, see `bind_listen`.
+const DEFAULT_HTTP_LISTEN: &str = "0.0.0.0:80,[::]:80";
+const DEFAULT_HTTPS_LISTEN: &str = "0.0.0.0:443,[::]:443";
+/// Dedicated account to run as when there is no invoking user to drop to.
+const SERVICE_USER: &str = "mimikry";
+
+/// `--user`, set once at startup so every step agrees on the real user.
+static USER_OVERRIDE: OnceLock<String> = OnceLock::new();
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Comma separated list of domains to fake (e.g. github.com,mysite.org)
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+/// What to fake and how; shared by serving until Ctrl+C and `run`.
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Comma separated list of domains to fake (e.g. github.com,mysite.org).
+    /// Optional if the config file sets `domains`.
     #[arg(index = 1)]
-    domains: String,
+    domains: Option<String>,
+
+    /// Config file [default: ./mimikry.toml, else /etc/mimikry/mimikry.toml
+    /// if present]. Command line flags override its settings.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Named `[profile.NAME]` of the config file to apply (e.g. vscode, pypi)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Artifact directory produced by vscsync (installers/, extensions/)
+    #[arg(long, default_value = "/artifacts")]
+    artifacts: PathBuf,
+
+    /// Existing CA certificate (PEM or PKCS#12) to sign leaves with instead of the Mimikry CA
+    #[arg(long, value_name = "FILE")]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM private key for --ca-cert (not needed for PKCS#12)
+    #[arg(long, value_name = "FILE", requires = "ca_cert")]
+    ca_key: Option<PathBuf>,
+
+    /// Ready-made leaf certificate chain (PEM or PKCS#12) to serve as-is.
+    /// PKCS#12 passwords are read from MIMIKRY_P12_PASSWORD.
+    #[arg(long, value_name = "FILE", conflicts_with = "ca_cert")]
+    cert: Option<PathBuf>,
+
+    /// PEM private key for --cert (not needed for PKCS#12)
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// User (name or uid) whose home is searched for artifacts and whose NSS
+    /// DBs get the CA. Defaults to the user who ran sudo/doas/pkexec.
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// User to serve as once ports are bound and trust is installed.
+    /// Defaults to the invoking user, or a `mimikry` account when run as root.
+    #[arg(long, value_name = "USER")]
+    run_as: Option<String>,
+
+    /// Keep serving as root instead of dropping privileges
+    #[arg(long, conflicts_with = "run_as")]
+    stay_root: bool,
+
+    /// Never touch the system or NSS trust stores
+    #[arg(long)]
+    no_trust: bool,
+
+    /// System trust store to install the CA into
+    #[arg(long, value_enum, default_value_t = TrustStore::Auto)]
+    trust_store: TrustStore,
+
+    /// Trust the CA in the NSS DBs (incl. Firefox profiles) of every human
+    /// user from /etc/passwd, not just the invoking one
+    #[arg(long)]
+    all_users: bool,
+
+    /// Also trust the CA in these runtimes' own stores
+    #[arg(long, value_enum, value_name = "RUNTIME", value_delimiter = ',')]
+    trust_runtimes: Vec<Runtime>,
+
+    /// Abort startup if the post-install TLS self-test fails for these stores
+    #[arg(long, value_enum, value_name = "STORE", value_delimiter = ',')]
+    require_trust: Vec<RequiredStore>,
+
+    /// How faked domains are made to resolve to mimikry
+    #[arg(long, value_enum, default_value_t = Resolver::Hosts)]
+    resolver: Resolver,
+
+    /// Address(es) faked domains resolve to, IPv4 and/or IPv6. Required with
+    /// --resolver dns, usually this machine's LAN address; defaults to
+    /// 127.0.0.1,::1 otherwise.
+    #[arg(long, value_name = "IP", value_delimiter = ',')]
+    target_ip: Vec<IpAddr>,
+
+    /// Address the DNS responder listens on [default: 0.0.0.0:53, or
+    /// 127.0.0.2:53 with --resolver resolved to stay clear of the resolved stub]
+    #[arg(long, value_name = "ADDR")]
+    dns_listen: Option<SocketAddr>,
+
+    /// Addresses the HTTP server listens on
+    #[arg(long, value_name = "ADDR", value_delimiter = ',', default_value = DEFAULT_HTTP_LISTEN)]
+    http_listen: Vec<SocketAddr>,
+
+    /// Addresses the HTTPS server listens on
+    #[arg(long, value_name = "ADDR", value_delimiter = ',', default_value = DEFAULT_HTTPS_LISTEN)]
+    https_listen: Vec<SocketAddr>,
+
+    /// Resolver that non-faked queries are forwarded to. Without it they get
+    /// NXDOMAIN, except with --resolver resolved, which defaults to the
+    /// upstream server systemd-resolved was using.
+    #[arg(long, value_name = "ADDR")]
+    dns_upstream: Option<SocketAddr>,
+
+    /// Also run a forward HTTP(S) proxy on these addresses, for clients that
+    /// honour `https_proxy` but whose hosts file cannot be changed. The first
+    /// one is advertised by /proxy.pac and /mimikry/vscode-settings.json.
+    #[arg(long, value_name = "ADDR", value_delimiter = ',')]
+    proxy_listen: Vec<SocketAddr>,
+
+    /// What the proxy does with hosts that are not faked [default: reject, or
+    /// tunnel for `run` without --rootless, whose command reaches every host
+    /// through the proxy]
+    #[arg(long, value_enum)]
+    proxy_policy: Option<ProxyPolicy>,
+
+    /// `[[assets]]` roots from the config file
+    #[arg(skip)]
+    asset_roots: Vec<AssetRoot>,
+
+    /// `[[route]]` proxy rules from the config file
+    #[arg(skip)]
+    proxy_routes: Vec<RouteRule>,
+
+    /// The config file `domains` came from, re-read on SIGHUP
+    #[arg(skip)]
+    domains_config: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Resolver {
+    /// Add entries to this machine's /etc/hosts
+    Hosts,
+    /// Answer DNS queries for the faked domains (and `*.` wildcards) on port 53
+    Dns,
+    /// Route the faked domains from systemd-resolved to a local DNS responder
+    Resolved,
+    /// Add `address=` entries to NetworkManager's dnsmasq
+    Dnsmasq,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Manage the persistent Mimikry root CA
+    Ca {
+        #[command(subcommand)]
+        action: CaAction,
+    },
+    /// Fake the domains for a single command only and exit with its status.
+    /// The command is pointed at a proxy on loopback and a throwaway CA
+    /// through its environment; nothing system wide changes and no root is
+    /// needed.
+    Run {
+        #[command(flatten)]
+        serve: ServeArgs,
+
+        /// Run the command in private user, mount and network namespaces
+        /// with their own hosts file and CA bundle instead, for commands that
+        /// ignore proxy variables. The command cannot reach the network.
+        #[arg(long)]
+        rootless: bool,
+
+        /// Command to run, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        exec: Vec<String>,
+    },
+    /// Undo every system change recorded by a previous (possibly crashed) run
+    Cleanup,
+    /// Internal: root helper that cleans up after the unprivileged server
+    #[command(hide = true)]
+    CleanupHelper,
+}
+
+#[derive(Subcommand, Debug)]
+enum CaAction {
+    /// Replace the CA with a freshly generated one and drop the old one from trust stores
+    Rotate {
+        /// Comma separated list of domains the new CA may sign for
+        #[arg(index = 1)]
+        domains: String,
+
+        /// System trust store the old CA is removed from
+        #[arg(long, value_enum, default_value_t = TrustStore::Auto)]
+        trust_store: TrustStore,
+
+        /// Remove the old CA from every human user's NSS DBs
+        #[arg(long)]
+        all_users: bool,
+    },
+}
+
+/// What the HTTPS listeners serve. `trust_anchor` is what clients must
+/// trust (None for a supplied leaf); `served_chain` is what to check.
+/// `resolver` mints the leaves, if they are minted, and can take new
+/// `patterns` while serving.
+struct TlsIdentity {
+    trust_anchor: Option<String>,
+    served_chain: String,
+    config: Arc<ServerConfig>,
+    patterns: tls::Patterns,
+    resolver: Option<Arc<tls::SniResolver>>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Shared state handed to every request handler.
+struct ServerState {
+    index: Arc<ArtifactIndex>,
+    gallery: Arc<Gallery>,
+    artifacts: PathBuf,
+    domains: tls::Patterns,
+    proxy: Option<ProxyEndpoint>,
+    _watcher: Arc<Mutex<RecommendedWatcher>>,
+}
+
+fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let Args { command, serve } = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // Flags of `run` are parsed by the subcommand
+    let serve_matches = matches.subcommand_matches("run").unwrap_or(&matches);
+
+    let args = match command {
+        Some(Commands::Run { serve, rootless: true, exec }) => {
+            let args = prepare_args(serve, serve_matches)?;
+            // A process can only enter a user namespace while single threaded
+            rootless::enter().context("Failed to enter rootless namespaces")?;
+            let code = runtime()?.block_on(run_rootless(args, exec))?;
+            std::process::exit(code);
+        }
+        Some(Commands::Run { serve, rootless: false, exec }) => {
+            let args = prepare_args(serve, serve_matches)?;
+            let code = runtime()?.block_on(run_scoped(args, exec))?;
+            std::process::exit(code);
+        }
+        None => serve,
+        Some(command) => {
+            require_root()?;
+            return runtime()?.block_on(run_command(command));
+        }
+    };
+
+    require_root()?;
+    let args = prepare_args(args, serve_matches)?;
+    if !args.stay_root {
+        // Must precede every thread, see drop_privileges
+        privdrop::forbid_new_privileges()?;
+    }
+    runtime()?.block_on(run(args))
+}
+
+fn require_root() -> Result<()> {
     if { users::get_current_uid() } != 0 {
-        return Err(anyhow::anyhow!("Root privileges required. Please run with sudo."));
+        return Err(anyhow::anyhow!(
+            "Root privileges required. Please run with sudo, or use `mimikry run` for a single command."
+        ));
     }
+    Ok(())
+}
 
-    let args = Args::parse();
-    let domains: Vec<String> = args.domains.split(',').map(|s| s.trim().to_string()).collect();
+/// Applies the config file, then resolves paths and the real user before
+/// anything else looks at them.
+fn prepare_args(mut args: ServeArgs, matches: &ArgMatches) -> Result<ServeArgs> {
+    config::apply(&mut args, matches).context("Invalid configuration")?;
+    if parse_domains(args.domains.as_deref().unwrap_or_default()).is_empty() {
+        return Err(anyhow::anyhow!("No domains to fake: pass them as the first argument or set `domains` in mimikry.toml"));
+    }
+
+    if let Ok(artifacts) = fs::canonicalize(&args.artifacts) {
+        args.artifacts = artifacts;
+    }
+
+    if let Some(user) = &args.user {
+        USER_OVERRIDE.get_or_init(|| user.clone());
+        if get_real_user().is_none() {
+            return Err(anyhow::anyhow!("Unknown user '{}'", user));
+        }
+    }
+    Ok(args)
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread().enable_all().build()?)
+}
+
+/// The maintenance subcommands.
+async fn run_command(command: Commands) -> Result<()> {
+    match command {
+        Commands::Ca { action: CaAction::Rotate { domains, trust_store, all_users } } => {
+            let _lock = StateLock::acquire()?;
+            rotate_ca(&parse_domains(&domains), trust_store, all_users)
+        }
+        Commands::Cleanup => {
+            let _lock = StateLock::acquire()?;
+            let journal = Journal::open().context("Failed to open state journal")?;
+            cleanup_system(&journal)?;
+            println!(">> System cleaned.");
+            Ok(())
+        }
+        Commands::CleanupHelper => {
+            // The state lock came with us from the server, see CleanupHelper
+            privdrop::wait_for_server();
+            // Re-read: the server journaled more after we started
+            let journal = Journal::open()?;
+            cleanup_system(&journal)
+        }
+        Commands::Run { .. } => unreachable!("handled in main"),
+    }
+}
+
+/// Fakes the domains system wide until Ctrl+C.
+async fn run(mut args: ServeArgs) -> Result<()> {
+    let lock = StateLock::acquire()?;
+    let journal = Journal::open().context("Failed to open state journal")?;
+
+    let domains = parse_domains(args.domains.as_deref().unwrap_or_default());
+    if args.target_ip.is_empty() {
+        if args.resolver == Resolver::Dns {
+            return Err(anyhow::anyhow!("--resolver dns needs --target-ip, the address clients should connect to"));
+        }
+        args.target_ip = loopback_targets();
+    }
 
     println!(">> Mimikry starting for domains: {:?}", domains);
 
     // 1. Clean up any previous run's mess just in case
-    cleanup_system().ok();
+    if !journal.is_empty() {
+        println!("   Previous run did not finish cleanly, replaying its journal");
+        journal.replay().context("Failed to undo previous run; try `mimikry cleanup`")?;
+    }
+    cleanup_hosts().ok();
 
-    // 2. Generate CA and Leaf Certificates
-    let (ca_cert_pem, _, leaf_cert_pem, leaf_key_pem) = generate_certs(&domains)?;
+    // 2. Pick the TLS identity
+    let identity = tls_identity(&args, &domains, false)?;
 
-    // 3. Install Trust
-    install_trust(&ca_cert_pem).context("Failed to install trust")?;
+    // From the first system change on, any failure must undo what was done
+    let mut cleanup_helper = None;
+    let result = serve_system(&args, &domains, &journal, &lock, identity, &mut cleanup_helper).await;
 
-    // 4. Update /etc/hosts
-    update_hosts(&domains).context("Failed to update /etc/hosts")?;
+    // 8. Cleanup
+    let cleaned = match cleanup_helper {
+        Some(helper) => helper.finish(),
+        None => cleanup_system(&journal),
+    };
+    if let (Err(_), Err(e)) = (&result, &cleaned) {
+        eprintln!("   Cleanup failed as well: {:#}; run `mimikry cleanup`", e);
+    }
+    result?;
+    cleaned?;
+    println!(">> System cleaned. Goodbye.");
 
-    // 5. Serve
-    let routes = warp::path::full()
-        .map(|path: warp::path::FullPath| path.as_str().to_string())
-        .and_then(handle_request)
-        .with(warp::log::custom(|info| {
-            println!("Request: {} {}", info.method(), info.path());
-        }));
+    Ok(())
+}
 
-    let server_http = warp::serve(routes.clone()).run(([0, 0, 0, 0], 80));
+/// Steps 3-7 of `run`: changes the system, serves, and on return (error or
+/// not) leaves the journal for the caller to replay. The cleanup helper, once
+/// spawned, is handed out through `cleanup_helper` for the same reason.
+async fn serve_system(
+    args: &ServeArgs,
+    domains: &[String],
+    journal: &Journal,
+    lock: &StateLock,
+    identity: TlsIdentity,
+    cleanup_helper: &mut Option<privdrop::CleanupHelper>,
+) -> Result<()> {
+    let TlsIdentity { trust_anchor, served_chain, config: tls_config, patterns, resolver } = identity;
 
-    let server_https = warp::serve(routes)
-        .tls()
-        .cert(&leaf_cert_pem)
-        .key(&leaf_key_pem)
-        .run(([0, 0, 0, 0], 443));
+    // 3. Install Trust, unless the chain is already trusted
+    if args.no_trust {
+        println!("   Skipping trust installation (--no-trust)");
+    } else if certs::is_system_trusted(&served_chain) {
+        println!("   Certificate chain is already trusted by the system store");
+    } else if let Some(ca_pem) = &trust_anchor {
+        let options = TrustOptions {
+            store: args.trust_store,
+            all_users: args.all_users,
+            runtimes: args.trust_runtimes.clone(),
+        };
+        trust::install_trust(&options, ca_pem, journal).context("Failed to install trust")?;
+    } else {
+        eprintln!("   Warning: supplied certificate chain is not trusted by the system store");
+    }
+
+    // 4. Make the domains resolve to us
+    let dns_responder = |upstream| Arc::new(dns::DnsServer::new(domains, args.target_ip.clone(), upstream));
+    let dns_server = match args.resolver {
+        Resolver::Hosts => {
+            update_hosts(domains, &args.target_ip, journal).context("Failed to update /etc/hosts")?;
+            None
+        }
+        Resolver::Dns => Some((dns_responder(args.dns_upstream), args.dns_listen.unwrap_or(([0, 0, 0, 0], 53).into()))),
+        Resolver::Resolved => {
+            let listen = args.dns_listen.unwrap_or(([127, 0, 0, 2], 53).into());
+            // Routing `~example.com` sends all of example.com our way, not just the faked names
+            let upstream = args.dns_upstream.or_else(|| resolver::resolved_upstream(listen)).context(
+                "--resolver resolved needs --dns-upstream: systemd-resolved knows no DNS server to forward other names to",
+            )?;
+            println!("   Forwarding names that are not faked to {}", upstream);
+            resolver::install_resolved_dropin(listen, domains, journal)
+                .context("Failed to configure systemd-resolved")?;
+            Some((dns_responder(Some(upstream)), listen))
+        }
+        Resolver::Dnsmasq => {
+            resolver::install_dnsmasq_snippet(domains, &args.target_ip, journal)
+                .context("Failed to configure NetworkManager's dnsmasq")?;
+            None
+        }
+    };
+
+    // 5. Index artifacts and load the extension gallery
+    let (proxy_listeners, proxies) = bind_proxies(args)?;
+    let state = load_state(args, &patterns, &proxies, Path::new(CACHE_DIR).join(INDEX_FILENAME)).await?;
+
+    // 6. Serve
+    let routes = routes(state.clone());
+    let http_listeners = bind_listen(&args.http_listen, DEFAULT_HTTP_LISTEN)?;
+    let server_http = future::join_all(
+        http_listeners
+            .into_iter()
+            .map(|listener| warp::serve(routes.clone()).run_incoming(TcpListenerStream::new(listener))),
+    );
+    let server_https =
+        spawn_tls_listeners(args, &patterns, proxy_listeners, tls_config, warp::service(routes.clone()))?;
+
+    let dns_sockets = match &dns_server {
+        Some((_, listen)) => Some(dns::DnsServer::bind(*listen)?),
+        None => None,
+    };
+
+    if let (false, Some(https)) = (args.no_trust, args.https_listen.first()) {
+        let nss_dbs = journal.nss_databases();
+        if let Err(e) = selftest::run(*https, domains, &nss_dbs, &args.require_trust).await {
+            server_https.abort();
+            return Err(e.context("Trust self-test failed"));
+        }
+    }
+
+    // 7. Everything needing root is done: bound sockets, trust, resolver.
+    // From here on only the cleanup helper keeps root.
+    let run_as = if args.stay_root { None } else { service_user(args.run_as.as_deref())? };
+    match &run_as {
+        Some(user) => {
+            *cleanup_helper = Some(privdrop::CleanupHelper::spawn(lock)?);
+            privdrop::give_dir(Path::new(CACHE_DIR), &[INDEX_FILENAME], user)?;
+            privdrop::drop_privileges(user)?;
+        }
+        None => eprintln!("   Warning: serving as root"),
+    }
+    spawn_domain_reload(args, resolver)?;
+
+    let server_dns = async {
+        match (dns_server, dns_sockets) {
+            (Some((dns, listen)), Some(sockets)) => {
+                println!("   Answering DNS for faked domains on {}", listen);
+                dns.serve(sockets).await
+            }
+            _ => std::future::pending().await,
+        }
+    };
+
+    println!(
+        ">> Server running on {:?} (HTTP) and {:?} (HTTPS). serving artifacts...",
+        args.http_listen, args.https_listen
+    );
 
-    println!(">> Server running on port 80 and 443. serving artifacts...");
     println!(">> Press Ctrl+C to shutdown.");
 
     tokio::select! {
         _ = server_http => {},
-        _ = server_https => {},
+        res = server_dns => {
+            if let Err(e) = res {
+                eprintln!("   DNS responder failed: {:#}", e);
+            }
+        },
+        res = server_https => {
+            if let Ok(Err(e)) = res {
+                eprintln!("   HTTPS listener failed: {:#}", e);
+            }
+        },
         _ = signal::ctrl_c() => {
             println!("\n>> Shutdown signal received.");
         }
     }
 
-    // 6. Cleanup
-    cleanup_system()?;
-    println!(">> System cleaned. Goodbye.");
-
+    if let Err(e) = state.index.save() {
+        eprintln!("   Warning: could not persist artifact index: {}", e);
+    }
     Ok(())
 }
 
-async fn handle_request(path: String) -> Result<impl warp::Reply, warp::Rejection> {
-    // Extract filename from the end of the URL
-    let filename = Path::new(&path)
-        .file_name()
-        .and_then(|s| s.to_str())
-        .unwrap_or("");
-
-    if filename.is_empty() {
-        return Err(warp::reject::not_found());
+/// `run` without --rootless: serves only through a proxy on loopback, which
+/// the command is pointed at along with a throwaway CA through its
+/// environment. Nothing system wide changes, so there is no journal and
+/// nothing to clean up. Returns the command's exit code.
+async fn run_scoped(mut args: ServeArgs, exec: Vec<String>) -> Result<i32> {
+    let domains = parse_domains(args.domains.as_deref().unwrap_or_default());
+    // Every host the command talks to goes through the proxy, not just ours
+    args.proxy_policy.get_or_insert(ProxyPolicy::Tunnel);
+    if args.proxy_listen.is_empty() {
+        args.proxy_listen = vec![SocketAddr::from(([127, 0, 0, 1], 0))];
     }
+    // Nothing resolves the domains to us, so listeners on 80/443 would only need root
+    args.http_listen.clear();
+    args.https_listen.clear();
 
-    // Define search paths
-    let mut search_dirs = Vec::new();
-    
-    // Attempt to get the REAL user's home dir (since we are running as root)
-    let real_user_home = get_real_user_home();
-    
-    if let Some(home) = &real_user_home {
-        search_dirs.push(home.join("Downloads"));
+    println!(">> Mimikry starting for one command, domains: {:?}", domains);
+
+    let TlsIdentity { trust_anchor, config: tls_config, patterns, .. } = tls_identity(&args, &domains, true)?;
+    let ca_env = match &trust_anchor {
+        Some(ca_pem) => Some(launch::ca_env(ca_pem)?),
+        None => {
+            eprintln!("   Warning: supplied certificate chain is not passed to the command");
+            None
+        }
+    };
+
+    let (proxy_listeners, proxies) = bind_proxies(&args)?;
+    let state = load_state(&args, &patterns, &proxies, rootless::cache_dir().join(INDEX_FILENAME)).await?;
+    let routes = routes(state.clone());
+    let server_https = spawn_tls_listeners(&args, &patterns, proxy_listeners, tls_config, warp::service(routes))?;
+
+    println!(">> Running {:?}", exec);
+    let mut child = launch::spawn(&exec, ca_env.as_ref(), &proxy_env(&proxies))?;
+
+    let outcome = tokio::select! {
+        code = launch::wait(&mut child) => code,
+        res = server_https => Err(listeners_failed(res)),
+    };
+    finish_command(&mut child, outcome, &state).await
+}
+
+/// `run --rootless`: serves inside the namespaces `main` entered, for the one
+/// command started there. Nothing outside them is changed, so there is no
+/// journal and nothing to clean up. Returns the command's exit code.
+async fn run_rootless(mut args: ServeArgs, exec: Vec<String>) -> Result<i32> {
+    let domains = parse_domains(args.domains.as_deref().unwrap_or_default());
+    if args.target_ip.is_empty() {
+        args.target_ip = loopback_targets();
     }
-    
-    // Add media (USB)
-    search_dirs.push(PathBuf::from("/media"));
 
-    // Add Env var
-    if let Ok(asset_dir) = env::var("MIMIKRY_ASSET_DIR") {
-        search_dirs.push(PathBuf::from(asset_dir));
+    println!(">> Mimikry starting rootless for domains: {:?}", domains);
+
+    // A throwaway CA: it is only ever trusted inside the namespace
+    let TlsIdentity { trust_anchor, config: tls_config, patterns, .. } = tls_identity(&args, &domains, true)?;
+    if trust_anchor.is_none() {
+        eprintln!("   Warning: supplied certificate chain is not added to the namespace's CA bundle");
     }
+    let sandbox = rootless::prepare(&domains, &args.target_ip, trust_anchor.as_deref())
+        .context("Failed to set up namespace hosts file and CA bundle")?;
 
-    println!("   Looking for artifact: '{}'", filename);
+    let (proxy_listeners, proxies) = bind_proxies(&args)?;
+    let state = load_state(&args, &patterns, &proxies, rootless::cache_dir().join(INDEX_FILENAME)).await?;
+    let routes = routes(state.clone());
+    let http_listeners = bind_listen(&args.http_listen, DEFAULT_HTTP_LISTEN)?;
+    let server_http = future::join_all(
+        http_listeners
+            .into_iter()
+            .map(|listener| warp::serve(routes.clone()).run_incoming(TcpListenerStream::new(listener))),
+    );
+    let server_https =
+        spawn_tls_listeners(&args, &patterns, proxy_listeners, tls_config, warp::service(routes.clone()))?;
 
-    for dir in search_dirs {
-        if !dir.exists() { continue; }
-        
-        // Recursive search in these directories
-        for entry in WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_name() == filename {
-                let full_path = entry.path();
-                println!("   Found at: {:?}", full_path);
-
-                let mime = mime_guess::from_path(full_path).first_or_octet_stream();
-                
-                // Read file
-                if let Ok(contents) = fs::read(full_path) {
-                    return Ok(warp::reply::with_header(
-                        contents,
-                        "Content-Type",
-                        mime.as_ref(),
-                    ));
-                }
-            }
+    if let (Some(_), Some(https)) = (&trust_anchor, args.https_listen.first()) {
+        if let Err(e) = selftest::run(*https, &domains, &[], &args.require_trust).await {
+            server_https.abort();
+            return Err(e.context("Trust self-test failed"));
         }
     }
 
-    Err(warp::reject::not_found())
+    println!(">> Running {:?}", exec);
+    let mut child = launch::spawn(&exec, sandbox.ca_env(), &proxy_env(&proxies))?;
+
+    let outcome = tokio::select! {
+        code = launch::wait(&mut child) => code,
+        _ = server_http => Err(anyhow::anyhow!("HTTP listeners stopped")),
+        res = server_https => Err(listeners_failed(res)),
+    };
+    finish_command(&mut child, outcome, &state).await
 }
 
-// --- Certificate Logic ---
+/// Ends a `run`: the command is killed if the servers failed under it.
+async fn finish_command(child: &mut Child, outcome: Result<i32>, state: &ServerState) -> Result<i32> {
+    if outcome.is_err() {
+        child.kill().await.ok();
+    }
+    if let Err(e) = state.index.save() {
+        eprintln!("   Warning: could not persist artifact index: {}", e);
+    }
+    let code = outcome?;
+    println!(">> Command exited with status {}", code);
+    Ok(code)
+}
 
-fn generate_certs(domains: &[String]) -> Result<(String, String, String, String)> {
-    // 1. Create a Self-Signed CA
-    let mut ca_params = CertificateParams::new(vec!["Mimikry Root CA".to_string()]);
-    ca_params.distinguished_name.push(DnType::OrganizationName, "Mimikry Internal");
-    ca_params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
-    
-    let ca_cert = Certificate::from_params(ca_params)?;
-    let ca_cert_pem = ca_cert.serialize_pem()?;
-    let ca_key_pem = ca_cert.serialize_private_key_pem();
+fn listeners_failed(res: Result<Result<()>, JoinError>) -> anyhow::Error {
+    match res {
+        Ok(Err(e)) => e.context("HTTPS listener failed"),
+        _ => anyhow::anyhow!("HTTPS listeners stopped"),
+    }
+}
 
-    // 2. Create Leaf Cert signed by CA
-    let mut leaf_params = CertificateParams::new(domains.to_vec());
-    let mut sans = vec![];
-    for d in domains {
-        sans.push(SanType::DnsName(d.clone()));
+/// Binds the proxy listeners ahead of the rest, as the PAC file and the
+/// command's environment need their addresses, ports picked by the kernel
+/// included.
+fn bind_proxies(args: &ServeArgs) -> Result<(Vec<TcpListener>, Vec<SocketAddr>)> {
+    let listeners = args.proxy_listen.iter().map(|addr| net::bind_tcp(*addr)).collect::<Result<Vec<_>>>()?;
+    let addrs = listeners.iter().map(|listener| listener.local_addr()).collect::<io::Result<Vec<_>>>()?;
+    let policy = args.proxy_policy.unwrap_or(ProxyPolicy::Reject);
+    for addr in &addrs {
+        println!("   Proxy listening on {} ({:?} for other hosts)", addr, policy);
     }
-    leaf_params.subject_alt_names = sans;
-    
-    let leaf_cert = Certificate::from_params(leaf_params)?;
-    let leaf_cert_pem = leaf_cert.serialize_pem_with_signer(&ca_cert)?;
-    let leaf_key_pem = leaf_cert.serialize_private_key_pem();
+    Ok((listeners, addrs))
+}
 
-    Ok((ca_cert_pem, ca_key_pem, leaf_cert_pem, leaf_key_pem))
+/// Starts the HTTPS listeners and the proxies on `proxy_listeners`, which
+/// share the TLS config. Spawned, so the self-test can handshake with them.
+fn spawn_tls_listeners<S>(
+    args: &ServeArgs,
+    patterns: &tls::Patterns,
+    proxy_listeners: Vec<TcpListener>,
+    tls_config: Arc<ServerConfig>,
+    service: S,
+) -> Result<JoinHandle<Result<()>>>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let https_listeners = bind_listen(&args.https_listen, DEFAULT_HTTPS_LISTEN)?;
+    let policy = args.proxy_policy.unwrap_or(ProxyPolicy::Reject);
+
+    let https = future::try_join_all(
+        https_listeners
+            .into_iter()
+            .map(|listener| tls::serve_tls(listener, tls_config.clone(), service.clone())),
+    );
+    let proxies = future::try_join_all(proxy_listeners.into_iter().map(|listener| {
+        let routes = args.proxy_routes.clone();
+        proxy::serve_proxy(listener, patterns.clone(), policy, routes, tls_config.clone(), service.clone())
+    }));
+
+    let server = tokio::spawn(async move {
+        future::try_join(https, proxies).await?;
+        Ok(())
+    });
+    Ok(server)
 }
 
-// --- System Trust Logic ---
+/// On SIGHUP, re-reads `domains` from the config file they came from and
+/// hands them to `resolver`, which the proxy and the PAC file follow too.
+/// The resolver backend is not touched: added names must already resolve
+/// here (e.g. under a faked wildcard) or be reached through the proxy.
+fn spawn_domain_reload(args: &ServeArgs, resolver: Option<Arc<tls::SniResolver>>) -> Result<()> {
+    // Domains from the command line, or a supplied leaf, cannot change
+    let (Some(path), Some(resolver)) = (args.domains_config.clone(), resolver) else { return Ok(()) };
+    let profile = args.profile.clone();
+    let mut hangups = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    println!("   Send SIGHUP to reload domains from {}", path.display());
 
-fn install_trust(ca_pem: &str) -> Result<()> {
-    // 1. System Store (Ubuntu)
-    let sys_cert_path = Path::new(SYSTEM_CERT_DIR).join(CA_CERT_FILENAME);
-    let mut file = File::create(&sys_cert_path)?;
-    file.write_all(ca_pem.as_bytes())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let reloaded = config::reload_domains(&path, profile.as_deref()).and_then(|domains| {
+                resolver.set_patterns(domains.clone())?;
+                Ok(domains)
+            });
+            match reloaded {
+                Ok(domains) => println!("   Reloaded domains: {:?}", domains),
+                Err(e) => eprintln!("   Warning: domains not reloaded, keeping the old ones: {:#}", e),
+            }
+        }
+    });
+    Ok(())
+}
 
-    println!("   Updating system CA store...");
-    let status = Command::new("update-ca-certificates").output()?;
-    if !status.status.success() {
-        return Err(anyhow::anyhow!("Failed to run update-ca-certificates"));
+/// Picks the TLS identity: a supplied leaf served as-is, or leaves minted per
+/// SNI hostname from a supplied CA or a Mimikry CA (the persistent one, or a
+/// throwaway one if `ephemeral`).
+fn tls_identity(args: &ServeArgs, domains: &[String], ephemeral: bool) -> Result<TlsIdentity> {
+    let patterns = Arc::new(RwLock::new(domains.to_vec()));
+    if let Some(cert) = &args.cert {
+        let (chain, key) = certs::load_external_leaf(cert, args.key.as_deref()).context("Failed to load certificate")?;
+        certs::verify_leaf_covers(&chain, domains)?;
+        let config = tls::static_config(&chain, &key).context("Failed to load certificate")?;
+        return Ok(TlsIdentity { trust_anchor: None, served_chain: chain, config, patterns, resolver: None });
     }
 
-    // 2. NSS DB (Chrome/VSCode)
-    // We need to do this for the SUDO_USER, not root
-    if let Some(home) = get_real_user_home() {
-        let nss_db_path = home.join(NSS_DB_DIR);
-        let nss_db_url = format!("sql:{}", nss_db_path.to_string_lossy());
-
-        // We need a temp file for certutil
-        let temp_ca_path = PathBuf::from("/tmp").join(CA_CERT_FILENAME);
-        fs::write(&temp_ca_path, ca_pem)?;
-
-        println!("   Importing to NSS DB at: {}", nss_db_url);
-        
-        // certutil -A -n "Mimikry CA" -t "C,," -i /tmp/mimikry-ca.crt -d sql:/home/user/.pki/nssdb
-        let status = Command::new("certutil")
-            .arg("-A")
-            .arg("-n")
-            .arg("Mimikry CA")
-            .arg("-t")
-            .arg("C,,")
-            .arg("-i")
-            .arg(&temp_ca_path)
-            .arg("-d")
-            .arg(&nss_db_url)
-            .output();
-
-        // It might fail if DB doesn't exist, we try our best.
-        if let Ok(out) = status {
-            if !out.status.success() {
-                eprintln!("   Warning: certutil failed: {}", String::from_utf8_lossy(&out.stderr));
+    let ca = match &args.ca_cert {
+        Some(ca_cert) => certs::load_external_ca(ca_cert, args.ca_key.as_deref()).context("Failed to load CA")?,
+        None if ephemeral => certs::ephemeral_ca(domains).context("Failed to generate CA")?,
+        None => certs::load_or_create_ca(domains).context("Failed to load CA")?,
+    };
+    let ca_pem = ca.cert_pem.clone();
+    let (config, resolver) = tls::sni_config(ca, patterns.clone());
+    Ok(TlsIdentity {
+        trust_anchor: Some(ca_pem.clone()),
+        served_chain: ca_pem,
+        config,
+        patterns,
+        resolver: Some(resolver),
+    })
+}
+
+/// Indexes the artifacts and loads the extension gallery, both of which keep
+/// themselves up to date from then on. `proxies` are the bound proxy
+/// addresses, the first of which the PAC file points clients at.
+async fn load_state(args: &ServeArgs, domains: &tls::Patterns, proxies: &[SocketAddr], db_path: PathBuf) -> Result<Arc<ServerState>> {
+    let roots = artifact_roots(&args.artifacts, &args.asset_roots);
+    let index = tokio::task::spawn_blocking(move || ArtifactIndex::build(roots, db_path))
+        .await?
+        .context("Failed to build artifact index")?;
+    let index = Arc::new(index);
+    let watcher = index.watch().context("Failed to watch artifact directories")?;
+
+    let gallery = Arc::new(Gallery::new(args.artifacts.clone()));
+    {
+        let gallery = gallery.clone();
+        tokio::task::spawn_blocking(move || gallery.reload()).await?;
+    }
+    gallery.spawn_reloader();
+
+    Ok(Arc::new(ServerState {
+        index,
+        gallery,
+        artifacts: args.artifacts.clone(),
+        domains: domains.clone(),
+        proxy: proxies.first().map(|addr| ProxyEndpoint {
+            port: addr.port(),
+            policy: args.proxy_policy.unwrap_or(ProxyPolicy::Reject),
+        }),
+        _watcher: watcher,
+    }))
+}
+
+fn routes(
+    state: Arc<ServerState>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone + Send + Sync + 'static {
+    let with_state = warp::any().map(move || state.clone());
+
+    let update_route = warp::get()
+        .and(warp::path!("api" / "update" / String / String / String))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_state.clone())
+        .then(
+            |platform: String, quality: String, commit: String, host: Option<String>, state: Arc<ServerState>| async move {
+                update::handle_update(&state.artifacts, &state.index, host.as_deref(), &platform, &quality, &commit).await
+            },
+        );
+
+    let gallery_route = warp::post()
+        .and(warp::path!("_apis" / "public" / "gallery" / "extensionquery"))
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json::<serde_json::Value>())
+        .and(warp::header::optional::<String>("host"))
+        .and(with_state.clone())
+        .map(|body: serde_json::Value, host: Option<String>, state: Arc<ServerState>| {
+            state.gallery.query(&body, host.as_deref().unwrap_or("marketplace.visualstudio.com"))
+        });
+
+    // Proxy configuration for clients whose hosts file cannot be changed
+    let pac_route = warp::get()
+        .and(warp::path!("proxy.pac"))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_state.clone())
+        .map(|host: Option<String>, state: Arc<ServerState>| {
+            pac::pac_file(&state.domains.read().unwrap(), state.proxy.as_ref(), host.as_deref())
+        });
+
+    let vscode_settings_route = warp::get()
+        .and(warp::path!("mimikry" / "vscode-settings.json"))
+        .and(warp::header::optional::<String>("host"))
+        .and(with_state.clone())
+        .map(|host: Option<String>, state: Arc<ServerState>| pac::vscode_settings(state.proxy.as_ref(), host.as_deref()));
+
+    let file_route = warp::path::full()
+        .map(|path: warp::path::FullPath| path.as_str().to_string())
+        .and(warp::method())
+        .and(serve::conditionals())
+        .and(with_state)
+        .and_then(handle_request);
+
+    update_route
+        .or(gallery_route)
+        .or(pac_route)
+        .or(vscode_settings_route)
+        .or(file_route)
+        .with(warp::log::custom(|info| {
+            println!("Request: {} {}", info.method(), info.path());
+        }))
+}
+
+async fn handle_request(
+    path: String,
+    method: Method,
+    cond: Conditionals,
+    state: Arc<ServerState>,
+) -> Result<Response<Body>, warp::Rejection> {
+    // Mirror paths (e.g. update payloads) are served relative to the artifacts dir
+    if let Some(relative) = path.strip_prefix("/artifacts/") {
+        let relative = Path::new(relative);
+        if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(warp::reject::not_found());
+        }
+
+        let full_path = state.artifacts.join(relative);
+        if let Some(entry) = state.index.lookup_path(&full_path) {
+            match serve::serve_file(&entry, &method, &cond).await {
+                Ok(reply) => return Ok(reply),
+                Err(e) => eprintln!("   Warning: failed to serve {:?}: {}", entry.path, e),
             }
         }
-        
-        let _ = fs::remove_file(temp_ca_path);
+        return Err(warp::reject::not_found());
     }
 
-    Ok(())
-}
+    // Extract filename from the end of the URL
+    let filename = Path::new(&path)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
 
-fn remove_trust() -> Result<()> {
-    // 1. Remove from System
-    let sys_cert_path = Path::new(SYSTEM_CERT_DIR).join(CA_CERT_FILENAME);
-    if sys_cert_path.exists() {
-        fs::remove_file(sys_cert_path)?;
+    if filename.is_empty() {
+        return Err(warp::reject::not_found());
     }
-    // We verify strict "fresh" removal
-    Command::new("update-ca-certificates")
-        .arg("--fresh")
-        .output()?;
 
-    // 2. Remove from NSS DB
-    if let Some(home) = get_real_user_home() {
-        let nss_db_path = home.join(NSS_DB_DIR);
-        let nss_db_url = format!("sql:{}", nss_db_path.to_string_lossy());
+    println!("   Looking for artifact: '{}'", filename);
+
+    if let Some(entry) = state.index.lookup(filename) {
+        println!("   Found at: {:?}", entry.path);
 
-        Command::new("certutil")
-            .arg("-D")
-            .arg("-n")
-            .arg("Mimikry CA")
-            .arg("-d")
-            .arg(nss_db_url)
-            .output()
-            .ok(); // Ignore errors if cert didn't exist
+        // Stream file
+        match serve::serve_file(&entry, &method, &cond).await {
+            Ok(reply) => return Ok(reply),
+            Err(e) => eprintln!("   Warning: failed to serve {:?}: {}", entry.path, e),
+        }
     }
 
+    Err(warp::reject::not_found())
+}
+
+// --- Certificate Logic ---
+
+fn rotate_ca(domains: &[String], store: TrustStore, all_users: bool) -> Result<()> {
+    println!(">> Rotating Mimikry CA");
+    let options = TrustOptions { store, all_users, runtimes: Vec::new() };
+
+    // Old CA must leave the trust stores before its files are gone
+    trust::remove_trust(&options, certs::stored_ca_pem().as_deref()).context("Failed to remove old CA from trust stores")?;
+    let ca = certs::rotate_ca(domains).context("Failed to generate new CA")?;
+
+    println!(">> New CA valid until {}. It will be trusted on the next run.", ca.not_after.date());
     Ok(())
 }
 
 // --- Hosts File Logic ---
 
-fn update_hosts(domains: &[String]) -> Result<()> {
-    let hosts_path = "/etc/hosts";
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(hosts_path)?;
-
-    for domain in domains {
-        writeln!(file, "127.0.0.1 {} {}", domain, MIMIKRY_TAG)?;
+fn update_hosts(domains: &[String], targets: &[IpAddr], journal: &Journal) -> Result<()> {
+    // The hosts file cannot express wildcards; those need --resolver dns
+    let (wildcards, exact): (Vec<&String>, Vec<&String>) = domains.iter().partition(|d| d.starts_with("*."));
+    for pattern in wildcards {
+        eprintln!("   Warning: '{}' cannot be put in /etc/hosts, use --resolver dns", pattern);
     }
-    
-    println!("   Added {} domains to /etc/hosts", domains.len());
+
+    let lines: Vec<String> = exact
+        .iter()
+        .flat_map(|d| targets.iter().map(move |ip| format!("{} {} {}", ip, d, MIMIKRY_TAG)))
+        .collect();
+    hosts::append_lines(Path::new(HOSTS_PATH), &lines, journal)?;
+
+    println!("   Added {} domains to /etc/hosts", exact.len());
     Ok(())
 }
 
 fn cleanup_hosts() -> Result<()> {
-    let hosts_path = "/etc/hosts";
-    let file = File::open(hosts_path)?;
-    let reader = BufReader::new(file);
-
-    let mut lines: Vec<String> = Vec::new();
-    let mut changed = false;
-
-    for line in reader.lines() {
-        let line = line?;
-        if line.trim().ends_with(MIMIKRY_TAG) {
-            changed = true;
-            continue; // Skip our lines
-        }
-        lines.push(line);
-    }
-
-    if changed {
-        let mut file = File::create(hosts_path)?;
-        for line in lines {
-            writeln!(file, "{}", line)?;
-        }
+    if hosts::remove_lines(Path::new(HOSTS_PATH), |line| line.trim().ends_with(MIMIKRY_TAG))? {
         println!("   Cleaned /etc/hosts");
     }
-
     Ok(())
 }
 
-fn cleanup_system() -> Result<()> {
+/// Restores everything the journal recorded, then sweeps any tagged hosts
+/// lines left by versions that predate the journal.
+fn cleanup_system(journal: &Journal) -> Result<()> {
+    journal.replay()?;
     cleanup_hosts()?;
-    remove_trust()?;
     Ok(())
 }
 
 // --- Utils ---
 
-fn get_real_user_home() -> Option<PathBuf> {
-    // Because we run as sudo, $HOME is /root. We want the user who called sudo.
-    env::var("SUDO_USER").ok().and_then(|username| {
-        // Simple heuristic: linux homes are usually /home/username
-        // A more robust way requires looking up /etc/passwd but this works 99% of time
-        let path = PathBuf::from("/home").join(username);
-        if path.exists() { Some(path) } else { None }
+/// Binds `addrs`. Only when they are the built-in `default` (not given on the
+/// command line or in mimikry.toml) may the IPv6 wildcard fail to bind.
+fn bind_listen(addrs: &[SocketAddr], default: &str) -> Result<Vec<TcpListener>> {
+    let default: Vec<SocketAddr> = default.split(',').filter_map(|addr| addr.parse().ok()).collect();
+    net::bind_tcp_all(addrs, addrs == default.as_slice())
+}
+
+/// Proxy variables pointing a child at the first of `proxies`, if any.
+fn proxy_env(proxies: &[SocketAddr]) -> Vec<(&'static str, String)> {
+    let Some(addr) = proxies.first() else { return Vec::new() };
+    let url = format!("http://{}", net::loopback_for(*addr));
+    ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+        .into_iter()
+        .map(|var| (var, url.clone()))
+        .collect()
+}
+
+/// Where faked domains point when no --target-ip is given.
+fn loopback_targets() -> Vec<IpAddr> {
+    vec![IpAddr::from([127, 0, 0, 1]), IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1])]
+}
+
+fn parse_domains(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// True if `host` is one of the faked domains. `*.example.com` matches any
+/// subdomain of example.com, at any depth.
+fn matches_domain(patterns: &[String], host: &str) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_prefix("*.") {
+        Some(suffix) => host.len() > suffix.len() + 1 && host.ends_with(&format!(".{}", suffix)),
+        None => pattern == host,
     })
+}
+
+/// Directories searched for artifacts, highest priority first. `extra` roots
+/// from the config file slot in between the built-in ones by priority.
+fn artifact_roots(artifacts: &Path, extra: &[AssetRoot]) -> Vec<PathBuf> {
+    let mut roots: Vec<(i32, PathBuf)> = Vec::new();
+
+    // Attempt to get the REAL user's home dir (since we are running as root)
+    if let Some(home) = get_real_user_home() {
+        roots.push((300, home.join("Downloads")));
+    }
+
+    // Add media (USB)
+    roots.push((200, PathBuf::from("/media")));
+
+    // Add Env var
+    if let Ok(asset_dir) = env::var("MIMIKRY_ASSET_DIR") {
+        roots.push((100, PathBuf::from(asset_dir)));
+    }
+
+    // The vscsync mirror, served by path under /artifacts/
+    roots.push((0, artifacts.to_path_buf()));
+
+    roots.extend(extra.iter().map(|root| (root.priority, root.path.clone())));
+    // Stable, so equal priorities keep the order above
+    roots.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+    roots.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The user mimikry acts for: `--user` if given, else whoever elevated us
+/// via sudo, doas or pkexec, else ourselves (e.g. root in a container).
+fn get_real_user() -> Option<User> {
+    if let Some(spec) = USER_OVERRIDE.get() {
+        return match spec.parse::<u32>() {
+            Ok(uid) => users::get_user_by_uid(uid),
+            Err(_) => users::get_user_by_name(spec),
+        };
+    }
+
+    let by_uid = |var: &str| env::var(var).ok()?.parse::<u32>().ok().and_then(users::get_user_by_uid);
+    let by_name = |var: &str| env::var(var).ok().and_then(|name| users::get_user_by_name(&name));
+
+    by_uid("SUDO_UID")
+        .or_else(|| by_name("SUDO_USER"))
+        .or_else(|| by_name("DOAS_USER"))
+        .or_else(|| by_uid("PKEXEC_UID"))
+        .or_else(|| users::get_user_by_uid(users::get_current_uid()))
+}
+
+/// Account the server drops to: `--run-as`, else the invoking user, else the
+/// dedicated service account. None means there is nobody to drop to.
+fn service_user(run_as: Option<&str>) -> Result<Option<User>> {
+    if let Some(spec) = run_as {
+        let user = match spec.parse::<u32>() {
+            Ok(uid) => users::get_user_by_uid(uid),
+            Err(_) => users::get_user_by_name(spec),
+        };
+        return user.map(Some).with_context(|| format!("Unknown user '{}'", spec));
+    }
+
+    Ok(get_real_user()
+        .filter(|user| user.uid() != 0)
+        .or_else(|| users::get_user_by_name(SERVICE_USER)))
+}
+
+fn get_real_user_home() -> Option<PathBuf> {
+    // Because we run elevated, $HOME is /root. We want the invoking user's,
+    // wherever the passwd database (files, LDAP, ...) puts it.
+    let home = get_real_user()?.home_dir().to_path_buf();
+    if home.exists() { Some(home) } else { None }
 }
\ No newline at end of file
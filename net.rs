@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const LISTEN_BACKLOG: i32 = 1024;
+/// Pause after a failed accept, so running out of fds does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Binds a TCP listener. IPv6 sockets are made v6-only so `0.0.0.0:443` and
+/// `[::]:443` can be bound side by side.
+pub fn bind_tcp(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into()).with_context(|| format!("Failed to bind tcp/{}", addr))?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Binds a TCP listener on each of `addrs`. With `v6_optional`, a `[::]`
+/// that fails because this host has no IPv6 is skipped with a warning.
+pub fn bind_tcp_all(addrs: &[SocketAddr], v6_optional: bool) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        match bind_tcp(*addr) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if v6_optional && addr.ip() == Ipv6Addr::UNSPECIFIED && no_ipv6(&e) => {
+                eprintln!("   Warning: not listening on {}, IPv6 is unavailable: {}", addr, e.root_cause());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(listeners)
+}
+
+/// IPv6 disabled (ipv6.disable=1 gives EAFNOSUPPORT) or without even `::1`.
+fn no_ipv6(e: &anyhow::Error) -> bool {
+    let errno = e.root_cause().downcast_ref::<io::Error>().and_then(io::Error::raw_os_error);
+    matches!(errno, Some(libc::EAFNOSUPPORT | libc::EADDRNOTAVAIL))
+}
+
+pub fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into()).with_context(|| format!("Failed to bind udp/{}", addr))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Next connection on `listener`. Accept errors (EMFILE, ENFILE,
+/// ECONNABORTED...) concern one connection or pass once others close, so they
+/// are logged and retried rather than taking the listener down.
+pub async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(conn) => return conn,
+            Err(e) => {
+                let addr = listener.local_addr().map_or_else(|_| "?".to_string(), |addr| addr.to_string());
+                eprintln!("   Warning: accept on {} failed: {}", addr, e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Wildcard listen addresses are reached via loopback.
+pub fn loopback_for(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::from([127, 0, 0, 1]), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1]), addr.port()),
+        _ => addr,
+    }
+}
+
+fn socket(addr: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
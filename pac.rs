@@ -0,0 +1,97 @@
+use warp::http::header::CONTENT_TYPE;
+use warp::http::uri::Authority;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::proxy::ProxyPolicy;
+
+const PAC_MIME: &str = "application/x-ns-proxy-autoconfig";
+
+/// Where clients reach our proxy, as seen from the host they fetched the PAC
+/// file or snippet from.
+pub struct ProxyEndpoint {
+    pub port: u16,
+    pub policy: ProxyPolicy,
+}
+
+impl ProxyEndpoint {
+    /// `host:port` of the proxy, taking the host from the request's Host
+    /// header since that is a name the client can already reach us by.
+    fn address(&self, host_header: Option<&str>) -> String {
+        let host = host_header
+            .and_then(|h| h.parse::<Authority>().ok())
+            .map(|authority| authority.host().to_string())
+            .unwrap_or_else(|| "127.0.0.1".to_string());
+        format!("{}:{}", host, self.port)
+    }
+}
+
+/// `/proxy.pac`: the faked domains go to our proxy, everything else DIRECT.
+pub fn pac_file(domains: &[String], proxy: Option<&ProxyEndpoint>, host: Option<&str>) -> Response {
+    let Some(proxy) = proxy else { return no_proxy() };
+
+    let conditions: Vec<String> = domains
+        .iter()
+        .map(|domain| match domain.strip_prefix("*.") {
+            // dnsDomainIs is a suffix match, so ".example.com" skips example.com itself
+            Some(suffix) => format!("dnsDomainIs(host, {})", js_string(&format!(".{}", suffix))),
+            None => format!("host == {}", js_string(domain)),
+        })
+        .collect();
+
+    let body = format!(
+        "// Generated by mimikry: faked domains via the mimikry proxy, the rest direct.\n\
+         function FindProxyForURL(url, host) {{\n    \
+             host = host.toLowerCase();\n    \
+             if ({}) {{\n        \
+                 return \"PROXY {}\";\n    \
+             }}\n    \
+             return \"DIRECT\";\n\
+         }}\n",
+        if conditions.is_empty() { "false".to_string() } else { conditions.join(" ||\n        ") },
+        proxy.address(host)
+    );
+    warp::reply::with_header(body, CONTENT_TYPE, PAC_MIME).into_response()
+}
+
+/// `/mimikry/vscode-settings.json`: a settings.json fragment pointing VS
+/// Code at our proxy, with the launch flag for using the PAC file instead.
+pub fn vscode_settings(proxy: Option<&ProxyEndpoint>, host: Option<&str>) -> Response {
+    let Some(proxy) = proxy else { return no_proxy() };
+
+    let address = proxy.address(host);
+    let pac_host = host.unwrap_or("127.0.0.1");
+    let caveat = match proxy.policy {
+        ProxyPolicy::Reject => {
+            "// This proxy refuses hosts it does not fake (--proxy-policy reject), so with\n\
+             // http.proxy set VS Code reaches nothing else. To keep other traffic\n\
+             // direct, drop these settings and start VS Code with the PAC file:\n"
+        }
+        ProxyPolicy::Tunnel => "// Alternatively, to send only the faked domains through mimikry:\n",
+    };
+
+    let body = format!(
+        "// Generated by mimikry. Merge into VS Code's settings.json.\n\
+         {caveat}\
+         //   code --proxy-pac-url=http://{pac_host}/proxy.pac\n\
+         {{\n    \
+             \"http.proxy\": {proxy},\n    \
+             \"http.proxySupport\": \"override\",\n    \
+             \"http.proxyStrictSSL\": true\n\
+         }}\n",
+        caveat = caveat,
+        pac_host = pac_host,
+        proxy = js_string(&format!("http://{}", address)),
+    );
+    warp::reply::with_header(body, CONTENT_TYPE, "application/json").into_response()
+}
+
+fn no_proxy() -> Response {
+    warp::reply::with_status("mimikry: no --proxy-listen configured\n", StatusCode::NOT_FOUND).into_response()
+}
+
+/// A JSON string literal, which is also a valid JavaScript one.
+fn js_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
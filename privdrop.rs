@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::{fchown, DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use users::User;
+
+use crate::journal::StateLock;
+
+/// Root process that outlives the privilege drop, with one job: replay the
+/// journal once the server is done. It waits for its stdin pipe to close,
+/// which happens on a clean shutdown as well as when the server dies. It
+/// inherits the state lock, so no other instance can start before it is done.
+pub struct CleanupHelper {
+    child: Child,
+}
+
+impl CleanupHelper {
+    pub fn spawn(lock: &StateLock) -> Result<Self> {
+        let lock_fd = lock.as_raw_fd();
+        let mut cmd = Command::new("/proc/self/exe");
+        cmd.arg("cleanup-helper")
+            .stdin(Stdio::piped())
+            // Own process group, so Ctrl+C on the terminal only reaches the server
+            .process_group(0);
+
+        // SAFETY: fcntl is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                // Keep the lock fd open across exec
+                if libc::fcntl(lock_fd, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn().context("Failed to start privileged cleanup helper")?;
+        Ok(CleanupHelper { child })
+    }
+
+    /// Tells the helper to clean up and waits for it.
+    pub fn finish(mut self) -> Result<()> {
+        drop(self.child.stdin.take());
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cleanup helper failed ({}); run `mimikry cleanup`", status));
+        }
+        Ok(())
+    }
+}
+
+/// Body of the `cleanup-helper` subcommand: blocks until the server closes
+/// the pipe.
+pub fn wait_for_server() {
+    let _ = io::stdin().read_to_end(&mut Vec::new());
+}
+
+/// Hands `dir` (created 0700 if needed) and the listed files in it to
+/// `user`, for state the unprivileged server keeps writing, such as the
+/// artifact index. The user owns that tree and may have planted symlinks or
+/// hard links in it, so nothing is followed: each file is opened with
+/// O_NOFOLLOW and chowned through its fd, and only if it is a plain file.
+pub fn give_dir(dir: &Path, files: &[&str], user: &User) -> Result<()> {
+    let (uid, gid) = (Some(user.uid()), Some(user.primary_group_id()));
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let dir_fd = open_nofollow(dir, libc::O_DIRECTORY).with_context(|| format!("Failed to open {:?}", dir))?;
+    fchown(&dir_fd, uid, gid)?;
+
+    for name in files {
+        let path = dir.join(name);
+        let file = match open_nofollow(&path, 0) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Refusing to hand over {:?}", path)),
+        };
+        let meta = file.metadata()?;
+        if !meta.is_file() || meta.nlink() != 1 {
+            return Err(anyhow::anyhow!("Refusing to hand over {:?}: not a plain file", path));
+        }
+        fchown(&file, uid, gid)?;
+    }
+    Ok(())
+}
+
+fn open_nofollow(path: &Path, flags: i32) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK | flags)
+        .open(path)
+}
+
+/// Sets PR_SET_NO_NEW_PRIVS. The flag is per thread and only inherited by
+/// threads created later, so this must run in `main` before the runtime (and
+/// the index, gallery and watcher threads) exist. From then on no thread can
+/// regain privileges through a setuid binary or file capabilities.
+pub fn forbid_new_privileges() -> Result<()> {
+    // SAFETY: plain syscall without pointers.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error()).context("PR_SET_NO_NEW_PRIVS");
+    }
+    Ok(())
+}
+
+/// Permanently switches the process to `user`, with no supplementary groups
+/// and no capabilities left.
+///
+/// The id changes go through glibc, which applies setgroups/setresgid/
+/// setresuid to every thread; leaving uid 0 clears each thread's permitted,
+/// effective and ambient sets. Bounding-set changes are per thread and only
+/// made here for the calling thread and its future children; the other
+/// threads are covered by `forbid_new_privileges`, which must have run first.
+/// Every thread is checked afterwards.
+pub fn drop_privileges(user: &User) -> Result<()> {
+    let (uid, gid) = (user.uid(), user.primary_group_id());
+
+    // SAFETY: plain syscalls without pointers other than the null group list.
+    unsafe {
+        // The bounding set can only be shrunk while we still hold CAP_SETPCAP
+        for cap in 0..=last_capability() {
+            libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0);
+        }
+        if libc::setgroups(0, std::ptr::null()) != 0 {
+            return Err(io::Error::last_os_error()).context("setgroups");
+        }
+        if libc::setresgid(gid, gid, gid) != 0 {
+            return Err(io::Error::last_os_error()).context("setresgid");
+        }
+        if libc::setresuid(uid, uid, uid) != 0 {
+            return Err(io::Error::last_os_error()).context("setresuid");
+        }
+        libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0);
+
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(anyhow::anyhow!("Regained root after dropping privileges"));
+        }
+    }
+    verify_all_threads(uid).context("Privilege drop did not reach every thread")?;
+
+    println!("   Dropped privileges to {} (uid {})", user.name().to_string_lossy(), uid);
+    Ok(())
+}
+
+/// Checks every thread's ids, capabilities and no_new_privs flag in
+/// /proc/self/task.
+fn verify_all_threads(uid: u32) -> Result<()> {
+    for task in fs::read_dir("/proc/self/task")?.filter_map(|e| e.ok()) {
+        let status = fs::read_to_string(task.path().join("status"))?;
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::trim)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let tid = task.file_name().to_string_lossy().to_string();
+        if field("Uid:").split_whitespace().any(|id| id != uid.to_string()) {
+            return Err(anyhow::anyhow!("thread {} still has uid {}", tid, field("Uid:")));
+        }
+        for caps in ["CapPrm:", "CapEff:", "CapAmb:"] {
+            if field(caps).trim_start_matches('0') != "" {
+                return Err(anyhow::anyhow!("thread {} still has {} {}", tid, caps, field(caps)));
+            }
+        }
+        if field("NoNewPrivs:") != "1" {
+            return Err(anyhow::anyhow!("thread {} lacks no_new_privs", tid));
+        }
+    }
+    Ok(())
+}
+
+fn last_capability() -> libc::c_ulong {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(40)
+}
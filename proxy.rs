@@ -0,0 +1,231 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use warp::http::header::{CONNECTION, HOST, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE};
+use warp::http::uri::Authority;
+use warp::http::{HeaderMap, Method, Request, Response, StatusCode};
+use warp::hyper::client::HttpConnector;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{self, Body, Client};
+
+use crate::tls::Patterns;
+
+/// What the proxy does with hosts that are not faked.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProxyPolicy {
+    /// Answer 403, so nothing reaches the real internet through mimikry
+    Reject,
+    /// Pass the connection through to the real host
+    Tunnel,
+}
+
+/// Policy for particular hosts that are not faked, from `[[route]]` tables
+/// in mimikry.toml. The first rule matching a host wins.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    /// Host patterns, `*.example.com` wildcards included
+    pub domains: Vec<String>,
+    pub action: ProxyPolicy,
+}
+
+struct Proxy {
+    patterns: Patterns,
+    policy: ProxyPolicy,
+    routes: Vec<RouteRule>,
+    acceptor: TlsAcceptor,
+    client: Client<HttpConnector>,
+}
+
+/// Runs a forward proxy on `listener`. CONNECTs to faked domains are
+/// terminated with the same TLS config as the HTTPS listeners and, like plain
+/// proxied requests for them, handed to `service`. Other hosts are handled
+/// per the first matching rule in `routes`, else per `policy`.
+pub async fn serve_proxy<S>(
+    listener: TcpListener,
+    patterns: Patterns,
+    policy: ProxyPolicy,
+    routes: Vec<RouteRule>,
+    tls: Arc<ServerConfig>,
+    service: S,
+) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let proxy = Arc::new(Proxy {
+        patterns,
+        policy,
+        routes,
+        acceptor: TlsAcceptor::from(tls),
+        client: Client::new(),
+    });
+
+    loop {
+        let (stream, peer) = crate::net::accept(&listener).await;
+        let proxy = proxy.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let handler = service_fn(move |req| {
+                let proxy = proxy.clone();
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(proxy.handle(req, service).await) }
+            });
+            let conn = Http::new().http1_only(true).serve_connection(stream, handler).with_upgrades();
+            if let Err(e) = conn.await {
+                eprintln!("   Proxy connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+impl Proxy {
+    async fn handle<S>(&self, mut req: Request<Body>, mut service: S) -> Response<Body>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        let Some((host, port)) = target(&req) else {
+            return status(StatusCode::BAD_REQUEST, "mimikry: no target host in request");
+        };
+        let faked = crate::matches_domain(&self.patterns.read().unwrap(), &host);
+
+        if !faked && self.policy_for(&host) == ProxyPolicy::Reject {
+            println!("   Proxy: refused {}:{}", host, port);
+            return status(StatusCode::FORBIDDEN, &format!("mimikry: {} is not served by this proxy", host));
+        }
+
+        if req.method() == Method::CONNECT {
+            return if faked { self.intercept(req, host, service) } else { tunnel(req, host, port).await };
+        }
+
+        // Credentials for the proxy must not travel on
+        req.headers_mut().remove(PROXY_AUTHORIZATION);
+        strip_hop_by_hop(req.headers_mut());
+
+        if faked {
+            // Absolute-form request, as sent to a proxy: served like a direct one
+            return match service.call(req).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+        }
+        match self.client.request(req).await {
+            Ok(mut response) => {
+                strip_hop_by_hop(response.headers_mut());
+                response
+            }
+            Err(e) => {
+                eprintln!("   Proxy: forwarding to {} failed: {}", host, e);
+                status(StatusCode::BAD_GATEWAY, "mimikry: upstream request failed")
+            }
+        }
+    }
+
+    fn policy_for(&self, host: &str) -> ProxyPolicy {
+        self.routes
+            .iter()
+            .find(|rule| crate::matches_domain(&rule.domains, host))
+            .map_or(self.policy, |rule| rule.action)
+    }
+
+    /// Accepts the CONNECT and plays the server inside the tunnel, with a
+    /// leaf minted for the SNI name.
+    fn intercept<S>(&self, req: Request<Body>, host: String, service: S) -> Response<Body>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        let acceptor = self.acceptor.clone();
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    eprintln!("   Proxy: CONNECT to {} was not upgraded: {}", host, e);
+                    return;
+                }
+            };
+            let tls = match acceptor.accept(upgraded).await {
+                Ok(tls) => tls,
+                Err(e) => {
+                    eprintln!("   Proxy: TLS handshake for {} failed: {}", host, e);
+                    return;
+                }
+            };
+            if let Err(e) = Http::new().serve_connection(tls, service).await {
+                eprintln!("   Proxy: connection for {} failed: {}", host, e);
+            }
+        });
+        Response::new(Body::empty())
+    }
+}
+
+/// Drops the headers that only describe one connection (RFC 9110 7.6.1),
+/// the ones `Connection` names included, before a message is passed on.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in named {
+        headers.remove(name.as_str());
+    }
+    for name in [CONNECTION, TE, TRAILER, UPGRADE, TRANSFER_ENCODING] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+}
+
+/// Splices a CONNECT through to the real host.
+async fn tunnel(req: Request<Body>, host: String, port: u16) -> Response<Body> {
+    let mut upstream = match TcpStream::connect((host.as_str(), port)).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!("   Proxy: could not reach {}:{}: {}", host, port, e);
+            return status(StatusCode::BAD_GATEWAY, "mimikry: upstream unreachable");
+        }
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(mut upgraded) => {
+                let _ = tokio::io::copy_bidirectional(&mut upgraded, &mut upstream).await;
+            }
+            Err(e) => eprintln!("   Proxy: CONNECT to {} was not upgraded: {}", host, e),
+        }
+    });
+    Response::new(Body::empty())
+}
+
+/// Host and port a proxied request is for: the CONNECT authority, the
+/// absolute-form URI, or failing both the Host header.
+fn target(req: &Request<Body>) -> Option<(String, u16)> {
+    let default_port = if req.method() == Method::CONNECT { 443 } else { 80 };
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => req.headers().get(HOST)?.to_str().ok()?.parse::<Authority>().ok()?,
+    };
+
+    let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    Some((host, authority.port_u16().unwrap_or(default_port)))
+}
+
+fn status(code: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(format!("{}\n", message)));
+    *response.status_mut() = code;
+    response
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::process::Command;
+
+use crate::journal::Journal;
+
+const RESOLVED_DROPIN: &str = "/etc/systemd/resolved.conf.d/mimikry.conf";
+const DNSMASQ_SNIPPET: &str = "/etc/NetworkManager/dnsmasq.d/mimikry.conf";
+/// systemd-resolved's view of the real upstream servers, stub excluded.
+const RESOLVED_UPSTREAMS: &str = "/run/systemd/resolve/resolv.conf";
+
+/// Routes the faked domains (and their subdomains) from systemd-resolved to
+/// the embedded responder on `listen`. Unlike /etc/hosts, this is honoured
+/// inside containers and snaps that use the resolved stub.
+pub fn install_resolved_dropin(listen: SocketAddr, domains: &[String], journal: &Journal) -> Result<()> {
+    let server = if listen.port() == 53 { listen.ip().to_string() } else { listen.to_string() };
+    let routing: Vec<String> = domains.iter().map(|d| format!("~{}", d.trim_start_matches("*."))).collect();
+
+    let contents = format!(
+        "# Managed by mimikry, removed on exit\n[Resolve]\nDNS={}\nDomains={}\n",
+        server,
+        routing.join(" ")
+    );
+    let reload = ["systemctl", "try-reload-or-restart", "systemd-resolved"];
+    install(Path::new(RESOLVED_DROPIN), &contents, &reload, journal)?;
+
+    println!("   Routed {} domains to {} via systemd-resolved", domains.len(), server);
+    Ok(())
+}
+
+/// First upstream server systemd-resolved currently uses, skipping our own
+/// responder at `ours` (left over in the list by a crashed run).
+pub fn resolved_upstream(ours: SocketAddr) -> Option<SocketAddr> {
+    let conf = fs::read_to_string(RESOLVED_UPSTREAMS).ok()?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        // Link-local servers carry a `%scope` this cannot express
+        .filter_map(|server| server.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .find(|server| *server != ours)
+}
+
+/// Answers the faked domains from NetworkManager's dnsmasq instance.
+/// `address=/example.com/` covers subdomains as well, so only wildcards map
+/// to it; exact names get a `host-record=`, which matches just that name.
+pub fn install_dnsmasq_snippet(domains: &[String], targets: &[IpAddr], journal: &Journal) -> Result<()> {
+    let mut contents = String::from("# Managed by mimikry, removed on exit\n");
+    for domain in domains {
+        for ip in targets {
+            match domain.strip_prefix("*.") {
+                Some(suffix) => contents.push_str(&format!("address=/{}/{}\n", suffix, ip)),
+                None => contents.push_str(&format!("host-record={},{}\n", domain, ip)),
+            }
+        }
+    }
+    check_nm_dnsmasq()?;
+    let reload = ["systemctl", "reload", "NetworkManager"];
+    install(Path::new(DNSMASQ_SNIPPET), &contents, &reload, journal)?;
+
+    println!("   Added {} domains to NetworkManager's dnsmasq", domains.len());
+    Ok(())
+}
+
+/// NetworkManager only reads dnsmasq.d when it runs dnsmasq itself, which
+/// takes `dns=dnsmasq` in its [main] section; otherwise the snippet would be
+/// silently ignored.
+fn check_nm_dnsmasq() -> Result<()> {
+    let out = Command::new("NetworkManager")
+        .arg("--print-config")
+        .output()
+        .context("Failed to run `NetworkManager --print-config`; is NetworkManager installed?")?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "`NetworkManager --print-config` failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+
+    let config = String::from_utf8_lossy(&out.stdout);
+    let mut section = "";
+    let mut dns = None;
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+        } else if let (true, Some((key, value))) = (section == "[main]", line.split_once('=')) {
+            if key.trim() == "dns" {
+                dns = Some(value.trim());
+            }
+        }
+    }
+
+    match dns {
+        Some("dnsmasq") => Ok(()),
+        other => Err(anyhow::anyhow!(
+            "NetworkManager is not using dnsmasq (dns={}); set dns=dnsmasq in its [main] section or pick another --resolver",
+            other.unwrap_or("default")
+        )),
+    }
+}
+
+/// Writes a config file and reloads its service, journaling both so cleanup
+/// restores the previous file (if any) and reloads again.
+fn install(path: &Path, contents: &str, reload: &[&str], journal: &Journal) -> Result<()> {
+    let dir = path.parent().context("Config path has no parent")?;
+    if !dir.parent().is_some_and(Path::exists) {
+        return Err(anyhow::anyhow!("{:?} not found; is the service installed?", dir.parent().unwrap_or(dir)));
+    }
+
+    journal.create_dir_all(dir)?;
+    journal.record_file(path, Some(reload.iter().map(|s| s.to_string()).collect()))?;
+
+    fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))?;
+
+    let out = Command::new(reload[0]).args(&reload[1..]).output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!("`{}` failed: {}", reload.join(" "), String::from_utf8_lossy(&out.stderr)));
+    }
+    Ok(())
+}
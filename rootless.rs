@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+use crate::launch::{self, CaEnv, SYSTEM_BUNDLES};
+use crate::MIMIKRY_TAG;
+
+const HOSTS_PATH: &str = "/etc/hosts";
+
+/// Moves this process into fresh user, mount and network namespaces. Must be
+/// called while still single threaded. Our uid maps to itself, so commands
+/// started inside run as the invoking user, while mimikry keeps the
+/// namespace capabilities it needs to bind 80/443 and mount over /etc files.
+pub fn enter() -> Result<()> {
+    let (uid, gid) = (users::get_current_uid(), users::get_current_gid());
+
+    // SAFETY: plain syscall, no pointers.
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("unshare failed; are unprivileged user namespaces enabled (kernel.unprivileged_userns_clone)?");
+    }
+
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+    fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+
+    // Keep our bind mounts from propagating back to the host
+    mount(None, Path::new("/"), libc::MS_REC | libc::MS_PRIVATE).context("Failed to make mounts private")?;
+    bring_up_loopback().context("Failed to bring up loopback")?;
+    Ok(())
+}
+
+/// Files mounted over the namespace's /etc, kept alive for the whole run.
+pub struct Sandbox {
+    _dir: TempDir,
+    ca: Option<CaEnv>,
+}
+
+/// Mounts a hosts file pointing the domains at `targets`, and (given a CA)
+/// system bundles that include it, over the originals.
+pub fn prepare(domains: &[String], targets: &[IpAddr], ca_pem: Option<&str>) -> Result<Sandbox> {
+    let dir = tempfile::Builder::new().prefix("mimikry-").tempdir()?;
+
+    let mut hosts = fs::read_to_string(HOSTS_PATH).unwrap_or_default();
+    if !hosts.is_empty() && !hosts.ends_with('\n') {
+        hosts.push('\n');
+    }
+    for domain in domains.iter().filter(|d| !d.starts_with("*.")) {
+        for ip in targets {
+            hosts.push_str(&format!("{} {} {}\n", ip, domain, MIMIKRY_TAG));
+        }
+    }
+    let hosts_file = dir.path().join("hosts");
+    fs::write(&hosts_file, hosts)?;
+    mount(Some(&hosts_file), Path::new(HOSTS_PATH), libc::MS_BIND).context("Failed to mount hosts file")?;
+
+    let Some(ca_pem) = ca_pem else {
+        return Ok(Sandbox { _dir: dir, ca: None });
+    };
+
+    for (i, path) in SYSTEM_BUNDLES.iter().enumerate() {
+        // Mount over the real file, not a symlink to it
+        let Ok(target) = fs::canonicalize(path) else { continue };
+        let Ok(contents) = fs::read_to_string(&target) else { continue };
+
+        let copy = dir.path().join(format!("bundle-{}.pem", i));
+        fs::write(&copy, launch::with_ca(contents, ca_pem))?;
+        mount(Some(&copy), &target, libc::MS_BIND).with_context(|| format!("Failed to mount over {:?}", target))?;
+    }
+
+    // For tools that bring their own roots
+    let ca = launch::ca_env(ca_pem)?;
+    Ok(Sandbox { _dir: dir, ca: Some(ca) })
+}
+
+impl Sandbox {
+    /// CA variables for commands started inside the namespaces.
+    pub fn ca_env(&self) -> Option<&CaEnv> {
+        self.ca.as_ref()
+    }
+}
+
+/// Per-user cache for the artifact index, as /var/cache is not ours.
+pub fn cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("mimikry")
+}
+
+fn mount(source: Option<&Path>, target: &Path, flags: libc::c_ulong) -> Result<()> {
+    let source = source.map(|s| CString::new(s.as_os_str().as_bytes())).transpose()?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+
+    // SAFETY: all pointers are valid NUL-terminated strings or null.
+    let rc = unsafe {
+        libc::mount(
+            source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// A new network namespace starts with `lo` down.
+fn bring_up_loopback() -> Result<()> {
+    // SAFETY: ifreq is plain data; the socket is closed on every path.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(b"lo\0") {
+            *dst = *src as libc::c_char;
+        }
+
+        let mut rc = libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut req);
+        if rc == 0 {
+            req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            rc = libc::ioctl(fd, libc::SIOCSIFFLAGS, &req);
+        }
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+
+        if rc != 0 {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
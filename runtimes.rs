@@ -0,0 +1,234 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::fsutil;
+use crate::journal::{Journal, Mutation};
+use crate::STATE_DIR;
+
+const KEYSTORE_ALIAS: &str = "mimikry-ca";
+const STOREPASS_ENV: &str = "MIMIKRY_JAVA_STOREPASS";
+const DEFAULT_STOREPASS: &str = "changeit";
+const JVM_DIR: &str = "/usr/lib/jvm";
+/// cacerts locations relative to a JDK (9+) or JRE (8) home.
+const CACERTS_PATHS: &[&str] = &["lib/security/cacerts", "jre/lib/security/cacerts"];
+
+const BUNDLE_BEGIN: &str = "# --- BEGIN mimikry CA (removed by `mimikry cleanup`) ---";
+const BUNDLE_END: &str = "# --- END mimikry CA ---";
+const PYTHON_LIB_DIRS: &[&str] = &["/usr/lib", "/usr/local/lib"];
+/// certifi bundles relative to a site-packages dir, including pip's vendored copy.
+const CERTIFI_BUNDLES: &[&str] = &["certifi/cacert.pem", "pip/_vendor/certifi/cacert.pem"];
+
+/// World-readable copy of the CA for runtimes that take a file path.
+const SHARED_CA_PATH: &str = "/etc/mimikry/mimikry-ca.pem";
+const NODE_ENV_PATH: &str = "/etc/mimikry/node.env";
+
+/// Language runtimes that ignore the OS trust store.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Runtime {
+    /// `cacerts` keystores of the JDKs under /usr/lib/jvm and $JAVA_HOME
+    Java,
+    /// certifi bundles used by pip and requests
+    Certifi,
+    /// An env file setting NODE_EXTRA_CA_CERTS for Node and Electron (VS Code)
+    Node,
+}
+
+pub fn install(runtime: Runtime, ca_pem: &str, journal: &Journal) -> Result<()> {
+    match runtime {
+        Runtime::Java => install_java(ca_pem, journal),
+        Runtime::Certifi => install_certifi(ca_pem, journal),
+        Runtime::Node => install_node(ca_pem, journal),
+    }
+}
+
+/// Reverts every runtime backend. Each step is a no-op where nothing was added.
+pub fn remove_all() {
+    for keystore in java_keystores() {
+        if remove_keystore_entry(&keystore).is_ok() {
+            println!("   Removed CA from {:?}", keystore);
+        }
+    }
+    for bundle in certifi_bundles() {
+        if let Err(e) = strip_bundle_block(&bundle) {
+            eprintln!("   Warning: could not clean {:?}: {}", bundle, e);
+        }
+    }
+    for path in [NODE_ENV_PATH, SHARED_CA_PATH] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// --- Java ---
+
+fn install_java(ca_pem: &str, journal: &Journal) -> Result<()> {
+    let keystores = java_keystores();
+    if keystores.is_empty() {
+        eprintln!("   Warning: no JDK keystores found");
+        return Ok(());
+    }
+
+    // keytool wants a file; keep it in our root-only dir
+    let mut pem_file = tempfile::NamedTempFile::new_in(STATE_DIR)?;
+    pem_file.write_all(ca_pem.as_bytes())?;
+
+    for keystore in keystores {
+        journal.record(Mutation::KeystoreEntry { keystore: keystore.clone() })?;
+
+        // Replace a CA from an earlier rotation, if any
+        let _ = remove_keystore_entry(&keystore);
+
+        let out = keytool(&keystore)
+            .args(["-importcert", "-noprompt", "-alias", KEYSTORE_ALIAS, "-file"])
+            .arg(pem_file.path())
+            .output()?;
+        if out.status.success() {
+            println!("   Imported CA into {:?}", keystore);
+        } else {
+            eprintln!("   Warning: keytool failed for {:?}: {}", keystore, String::from_utf8_lossy(&out.stdout).trim());
+        }
+    }
+    Ok(())
+}
+
+pub fn remove_keystore_entry(keystore: &Path) -> Result<()> {
+    let out = keytool(keystore).args(["-delete", "-alias", KEYSTORE_ALIAS]).output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!("keytool: {}", String::from_utf8_lossy(&out.stdout).trim()));
+    }
+    Ok(())
+}
+
+/// keytool invocation against `keystore`, preferring the owning JDK's binary.
+fn keytool(keystore: &Path) -> Command {
+    let bundled = keystore
+        .ancestors()
+        .map(|dir| dir.join("bin/keytool"))
+        .find(|bin| bin.is_file());
+
+    let storepass = env::var(STOREPASS_ENV).unwrap_or_else(|_| DEFAULT_STOREPASS.to_string());
+    let mut cmd = Command::new(bundled.unwrap_or_else(|| PathBuf::from("keytool")));
+    cmd.arg("-keystore").arg(keystore);
+    // Through the environment: argv is readable by every user via /proc
+    cmd.env(STOREPASS_ENV, storepass).arg("-storepass:env").arg(STOREPASS_ENV);
+    cmd
+}
+
+/// Distinct cacerts files. Distros often symlink several JDKs to one shared
+/// keystore, so paths are deduplicated after resolving links.
+fn java_keystores() -> Vec<PathBuf> {
+    let mut homes: Vec<PathBuf> = fs::read_dir(JVM_DIR)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    if let Ok(java_home) = env::var("JAVA_HOME") {
+        homes.push(PathBuf::from(java_home));
+    }
+
+    let mut seen = HashSet::new();
+    homes
+        .iter()
+        .flat_map(|home| CACERTS_PATHS.iter().map(move |rel| home.join(rel)))
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .filter(|path| path.is_file() && seen.insert(path.clone()))
+        .collect()
+}
+
+// --- Python certifi ---
+
+fn install_certifi(ca_pem: &str, journal: &Journal) -> Result<()> {
+    let bundles = certifi_bundles();
+    if bundles.is_empty() {
+        eprintln!("   Warning: no certifi bundles found");
+        return Ok(());
+    }
+
+    for bundle in bundles {
+        journal.record(Mutation::BundleBlock { path: bundle.clone() })?;
+
+        strip_bundle_block(&bundle)?;
+        let mut contents = fs::read_to_string(&bundle)?;
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&format!("{}\n{}", BUNDLE_BEGIN, ca_pem));
+        if !ca_pem.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(BUNDLE_END);
+        contents.push('\n');
+
+        fsutil::replace(&bundle, contents.as_bytes())?;
+        println!("   Appended CA to {:?}", bundle);
+    }
+    Ok(())
+}
+
+/// Cuts the marked mimikry block out of a PEM bundle, if present.
+pub fn strip_bundle_block(bundle: &Path) -> Result<()> {
+    let contents = fs::read_to_string(bundle)?;
+    let Some(start) = contents.find(BUNDLE_BEGIN) else { return Ok(()) };
+    let end = contents[start..]
+        .find(BUNDLE_END)
+        .map(|i| start + i + BUNDLE_END.len())
+        .context("Unterminated mimikry block")?;
+    let end = if contents[end..].starts_with('\n') { end + 1 } else { end };
+
+    fsutil::replace(bundle, format!("{}{}", &contents[..start], &contents[end..]).as_bytes())?;
+    Ok(())
+}
+
+/// Writable certifi bundles of the system and local Pythons. Bundles that
+/// are symlinks into the OS store (as on Debian) are skipped; the system
+/// backend already covers them.
+fn certifi_bundles() -> Vec<PathBuf> {
+    let mut site_dirs = Vec::new();
+    for lib in PYTHON_LIB_DIRS {
+        let Ok(entries) = fs::read_dir(lib) else { continue };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.file_name().to_string_lossy().starts_with("python3") {
+                site_dirs.push(entry.path().join("site-packages"));
+                site_dirs.push(entry.path().join("dist-packages"));
+            }
+        }
+    }
+    let python = Command::new("python3").args(["-c", "import certifi; print(certifi.where())"]).output();
+    let mut bundles: Vec<PathBuf> = match python {
+        Ok(out) if out.status.success() => vec![PathBuf::from(String::from_utf8_lossy(&out.stdout).trim())],
+        _ => Vec::new(),
+    };
+
+    bundles.extend(site_dirs.iter().flat_map(|dir| CERTIFI_BUNDLES.iter().map(move |rel| dir.join(rel))));
+
+    let mut seen = HashSet::new();
+    bundles
+        .into_iter()
+        .filter(|path| path.is_file() && !path.is_symlink())
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+// --- Node ---
+
+fn install_node(ca_pem: &str, journal: &Journal) -> Result<()> {
+    let ca_path = Path::new(SHARED_CA_PATH);
+    let env_path = Path::new(NODE_ENV_PATH);
+    if let Some(dir) = ca_path.parent() {
+        journal.create_dir_all(dir)?;
+    }
+
+    for path in [ca_path, env_path] {
+        journal.record_file(path, None)?;
+    }
+    fs::write(ca_path, ca_pem)?;
+    fs::write(env_path, format!("NODE_EXTRA_CA_CERTS={}\n", SHARED_CA_PATH))?;
+
+    println!("   Wrote {} (load it with `set -a; . {}; set +a`)", NODE_ENV_PATH, NODE_ENV_PATH);
+    Ok(())
+}
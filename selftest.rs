@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rustls::{ClientConfig, RootCertStore, ServerName};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::{net, trust};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Trust stores whose self-test failure aborts startup.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequiredStore {
+    /// The OS store (what curl, git and most tools use)
+    System,
+    /// Every NSS DB the CA was imported into
+    Nss,
+}
+
+struct Outcome {
+    store: String,
+    kind: RequiredStore,
+    domain: String,
+    result: Result<()>,
+}
+
+/// Handshakes with our own HTTPS listener for every domain, once against the
+/// system store and once per NSS DB, and prints a pass/fail table. Errors if
+/// a store listed in `required` failed. For an NSS DB, NSS itself verifies
+/// the leaf served in the handshake against the DB.
+pub async fn run(https: SocketAddr, domains: &[String], nss_dbs: &[String], required: &[RequiredStore]) -> Result<()> {
+    let target = net::loopback_for(https);
+    let mut outcomes = Vec::new();
+
+    let system_roots = rustls_native_certs::load_native_certs()
+        .map(|certs| certs.into_iter().map(|c| c.0).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for domain in domains {
+        outcomes.push(Outcome {
+            store: "system".to_string(),
+            kind: RequiredStore::System,
+            domain: domain.clone(),
+            result: handshake(target, domain, &system_roots).await.map(drop),
+        });
+    }
+
+    for db in nss_dbs {
+        let roots = nss_ca(db);
+        for domain in domains {
+            let result = match &roots {
+                Ok(roots) => match handshake(target, domain, roots).await {
+                    Ok(leaf) => nss_verify(db, &leaf),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(anyhow::anyhow!("{}", e)),
+            };
+            outcomes.push(Outcome {
+                store: db.clone(),
+                kind: RequiredStore::Nss,
+                domain: domain.clone(),
+                result,
+            });
+        }
+    }
+
+    print_table(&outcomes);
+
+    let failed: Vec<&Outcome> = outcomes.iter().filter(|o| o.result.is_err() && required.contains(&o.kind)).collect();
+    if let Some(first) = failed.first() {
+        return Err(anyhow::anyhow!(
+            "{} required trust check(s) failed, e.g. {} for {}",
+            failed.len(),
+            first.store,
+            first.domain
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the DER leaf the listener served.
+async fn handshake(target: SocketAddr, domain: &str, roots: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut store = RootCertStore::empty();
+    store.add_parsable_certificates(roots);
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+
+    // Wildcards are checked with a made-up name below them
+    let host = match domain.strip_prefix("*.") {
+        Some(suffix) => format!("mimikry-selftest.{}", suffix),
+        None => domain.to_string(),
+    };
+    let name = ServerName::try_from(host.as_str()).context("Invalid domain")?;
+
+    let attempt = async {
+        let stream = TcpStream::connect(target).await?;
+        let tls = TlsConnector::from(Arc::new(config)).connect(name, stream).await?;
+        Ok::<_, std::io::Error>(tls.get_ref().1.peer_certificates().and_then(|chain| chain.first()).map(|leaf| leaf.0.clone()))
+    };
+    let leaf = tokio::time::timeout(HANDSHAKE_TIMEOUT, attempt).await.context("Handshake timed out")??;
+    leaf.context("No certificate served")
+}
+
+/// The CA as stored in `db`, if trusted there for TLS servers. The handshake
+/// checks the served chain and host name against it, `nss_verify` that the
+/// DB as a whole accepts the leaf.
+fn nss_ca(db: &str) -> Result<Vec<Vec<u8>>> {
+    let listing = trust::certutil(db).args(["-L", "-d", db]).output()?;
+    let listing = String::from_utf8_lossy(&listing.stdout);
+    let flags = listing
+        .lines()
+        .find_map(|line| line.strip_prefix("Mimikry CA"))
+        .map(str::trim)
+        .context("CA not in DB")?;
+    if !flags.starts_with('C') {
+        return Err(anyhow::anyhow!("CA not trusted for TLS (flags {})", flags));
+    }
+
+    let out = trust::certutil(db).args(["-L", "-r", "-n", "Mimikry CA", "-d", db]).output()?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!("certutil: {}", String::from_utf8_lossy(&out.stderr).trim()));
+    }
+    Ok(vec![out.stdout])
+}
+
+/// Has NSS verify `leaf` for TLS server use against `db`, with the DB's own
+/// trust flags and name constraint checks.
+fn nss_verify(db: &str, leaf: &[u8]) -> Result<()> {
+    let mut file = tempfile::Builder::new().prefix("mimikry-leaf-").suffix(".der").tempfile()?;
+    file.write_all(leaf)?;
+    // vfychain runs as the DB's owner; the leaf is no secret
+    file.as_file().set_permissions(fs::Permissions::from_mode(0o644))?;
+
+    // -u 1: certUsageSSLServer
+    let out = trust::nss_tool("vfychain", db)
+        .args(["-d", db, "-u", "1"])
+        .arg(file.path())
+        .output()
+        .context("Failed to run vfychain")?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    if !out.status.success() || !stdout.contains("Chain is good") {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        // NSS's error (e.g. "ERROR -8172: ...") ends its stdout report
+        let last = |text: &str| text.lines().map(str::trim).rev().find(|line| !line.is_empty()).map(String::from);
+        let reason = last(&stderr).or_else(|| last(&stdout));
+        return Err(anyhow::anyhow!("vfychain: {}", reason.as_deref().unwrap_or("chain is bad")));
+    }
+    Ok(())
+}
+
+fn print_table(outcomes: &[Outcome]) {
+    let store_width = outcomes.iter().map(|o| o.store.len()).max().unwrap_or(0).max("STORE".len());
+    let domain_width = outcomes.iter().map(|o| o.domain.len()).max().unwrap_or(0).max("DOMAIN".len());
+
+    println!("   Trust self-test:");
+    println!("   {:<sw$}  {:<dw$}  RESULT", "STORE", "DOMAIN", sw = store_width, dw = domain_width);
+    for o in outcomes {
+        let result = match &o.result {
+            Ok(()) => "pass".to_string(),
+            Err(e) => format!("FAIL ({:#})", e),
+        };
+        println!("   {:<sw$}  {:<dw$}  {}", o.store, o.domain, result, sw = store_width, dw = domain_width);
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::Result;
+use std::io::SeekFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use warp::http::{header, Method, Response, StatusCode};
+use warp::hyper::Body;
+use warp::Filter;
+
+use crate::index::ArtifactEntry;
+
+/// Request headers that decide whether (and which part of) a file is sent.
+#[derive(Debug, Default)]
+pub struct Conditionals {
+    range: Option<String>,
+    if_range: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+pub fn conditionals() -> impl Filter<Extract = (Conditionals,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("range")
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .map(|range, if_range, if_none_match, if_modified_since| Conditionals {
+            range,
+            if_range,
+            if_none_match,
+            if_modified_since,
+        })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Streams an indexed artifact from disk, honouring Range/If-Range and the
+/// ETag/Last-Modified validators. HEAD gets the same headers without a body.
+/// The validators come from the open file; the indexed hash is only sent as
+/// the ETag while the entry still matches it.
+pub async fn serve_file(entry: &ArtifactEntry, method: &Method, cond: &Conditionals) -> Result<Response<Body>> {
+    let mut file = File::open(&entry.path).await?;
+    let meta = file.metadata().await?;
+    let len = meta.len();
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    // A file rewritten in place is served before the index catches up, and
+    // its old hash would then vouch for bytes it does not describe
+    let modified = UNIX_EPOCH + Duration::from_secs(mtime);
+    let etag = (len == entry.size && mtime == entry.mtime).then(|| format!("\"{}\"", entry.sha256));
+    let mime = mime_guess::from_path(&entry.path).first_or_octet_stream();
+
+    let mut builder = Response::builder()
+        .header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified))
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(etag) = &etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+    let etag = etag.as_deref();
+
+    if not_modified(cond, etag, modified) {
+        return Ok(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())?);
+    }
+
+    // A stale If-Range means the client's partial copy is outdated: send it all.
+    let range = match &cond.range {
+        Some(r) if if_range_matches(cond.if_range.as_deref(), etag, modified) => parse_range(r, len),
+        _ => ByteRange::Full,
+    };
+
+    let (builder, start, count) = match range {
+        ByteRange::Full => (builder.status(StatusCode::OK), 0, len),
+        ByteRange::Partial(start, end) => (
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len)),
+            start,
+            end - start + 1,
+        ),
+        ByteRange::Unsatisfiable => {
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())?);
+        }
+    };
+
+    let builder = builder
+        .header(header::CONTENT_TYPE, mime.as_ref())
+        .header(header::CONTENT_LENGTH, count);
+
+    if *method == Method::HEAD {
+        return Ok(builder.body(Body::empty())?);
+    }
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let stream = ReaderStream::new(file.take(count));
+    Ok(builder.body(Body::wrap_stream(stream))?)
+}
+
+fn not_modified(cond: &Conditionals, etag: Option<&str>, modified: SystemTime) -> bool {
+    // If-None-Match takes precedence; If-Modified-Since is ignored when present.
+    if let Some(tags) = &cond.if_none_match {
+        return tags
+            .split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || Some(t.trim_start_matches("W/")) == etag);
+    }
+
+    match cond.if_modified_since.as_deref().map(httpdate::parse_http_date) {
+        Some(Ok(since)) => modified <= since,
+        _ => false,
+    }
+}
+
+fn if_range_matches(if_range: Option<&str>, etag: Option<&str>, modified: SystemTime) -> bool {
+    match if_range {
+        None => true,
+        // If-Range requires a strong comparison, so weak tags never match.
+        Some(v) if v.starts_with('"') => Some(v) == etag,
+        Some(v) => httpdate::parse_http_date(v).map(|d| d == modified).unwrap_or(false),
+    }
+}
+
+/// Parses a single `bytes=` range. Malformed or multi-range headers are
+/// ignored (a full response is always a valid answer to Range).
+fn parse_range(value: &str, len: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else { return ByteRange::Full };
+    if spec.contains(',') { return ByteRange::Full; }
+    let Some((start, end)) = spec.split_once('-') else { return ByteRange::Full };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: the last N bytes
+        let Ok(suffix) = end.parse::<u64>() else { return ByteRange::Full };
+        if suffix == 0 || len == 0 { return ByteRange::Unsatisfiable; }
+        return ByteRange::Partial(len - suffix.min(len), len - 1);
+    }
+
+    let Ok(start) = start.parse::<u64>() else { return ByteRange::Full };
+    if start >= len { return ByteRange::Unsatisfiable; }
+
+    let end = if end.is_empty() {
+        len - 1
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end.min(len - 1),
+            _ => return ByteRange::Full,
+        }
+    };
+
+    ByteRange::Partial(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_within_the_file() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+        // An end past EOF is cut to the last byte
+        assert_eq!(parse_range("bytes=500-5000", 1000), ByteRange::Partial(500, 999));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        // Longer than the file: all of it
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Partial(0, 999));
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn starts_past_eof_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=2000-3000", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_or_multiple_ranges_get_the_whole_file() {
+        for value in ["items=0-1", "bytes=0-1,5-6", "bytes=abc-", "bytes=5-1", "bytes=-", "bytes=0"] {
+            assert_eq!(parse_range(value, 1000), ByteRange::Full, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{PrivateKey, ServerConfig};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, RwLock};
+use time::OffsetDateTime;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use warp::http::{Request, Response};
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::Service;
+use warp::hyper::Body;
+
+use crate::certs::{self, CertificateAuthority};
+use crate::net;
+
+/// Leaves kept at once. Hosts come from whatever SNI clients send (any name
+/// under a wildcard), so the least recently used are dropped beyond this.
+const CACHE_CAPACITY: usize = 1024;
+
+/// The faked domain patterns, shared by the TLS listeners, the proxy and the
+/// PAC file so that `SniResolver::set_patterns` reaches all of them.
+pub type Patterns = Arc<RwLock<Vec<String>>>;
+
+/// Picks the certificate per SNI hostname, minting (and caching) a leaf for
+/// just that name. Clients never see the other spoofed domains. The patterns
+/// can be swapped while serving (see `set_patterns`), within what the CA's
+/// name constraints cover.
+pub struct SniResolver {
+    ca: CertificateAuthority,
+    /// The CA and its intermediates, sent after each leaf
+    chain: Vec<rustls::Certificate>,
+    patterns: Patterns,
+    cache: Mutex<LeafCache>,
+}
+
+#[derive(Default)]
+struct LeafCache {
+    leaves: HashMap<String, CachedLeaf>,
+    /// Bumped on every use, to find the least recently used leaf
+    clock: u64,
+}
+
+struct CachedLeaf {
+    key: Arc<CertifiedKey>,
+    /// Past this the leaf is minted afresh, well before clients reject it
+    renew_at: OffsetDateTime,
+    last_used: u64,
+}
+
+impl LeafCache {
+    fn get(&mut self, host: &str, now: OffsetDateTime) -> Option<Arc<CertifiedKey>> {
+        self.clock += 1;
+        match self.leaves.get_mut(host) {
+            Some(leaf) if leaf.renew_at > now => {
+                leaf.last_used = self.clock;
+                Some(leaf.key.clone())
+            }
+            Some(_) => {
+                self.leaves.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, host: String, key: Arc<CertifiedKey>, renew_at: OffsetDateTime) {
+        if self.leaves.len() >= CACHE_CAPACITY && !self.leaves.contains_key(&host) {
+            // A linear scan, but only ever next to a far costlier signature
+            let oldest = self.leaves.iter().min_by_key(|(_, leaf)| leaf.last_used).map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                self.leaves.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.leaves.insert(host, CachedLeaf { key, renew_at, last_used: self.clock });
+    }
+}
+
+impl SniResolver {
+    pub fn new(ca: CertificateAuthority, patterns: Patterns) -> Self {
+        // Already parsed once when the CA was loaded
+        let chain = ca
+            .chain_pem
+            .iter()
+            .flat_map(|pem| rustls_pemfile::certs(&mut pem.as_bytes()).unwrap_or_default())
+            .map(rustls::Certificate)
+            .collect();
+        SniResolver {
+            ca,
+            chain,
+            patterns,
+            cache: Mutex::new(LeafCache::default()),
+        }
+    }
+
+    /// Replaces the patterns for the handshakes to come. Refused as a whole if
+    /// a domain lies outside our CA's name constraints, as clients would
+    /// reject its leaves; a supplied CA's own constraints are left to clients.
+    pub fn set_patterns(&self, patterns: Vec<String>) -> Result<()> {
+        if let Some(permitted) = &self.ca.permitted {
+            if let Some(outside) = patterns.iter().find(|d| !certs::is_permitted(permitted, d)) {
+                return Err(anyhow::anyhow!(
+                    "'{}' is outside the CA's name constraints {:?}; run `mimikry ca rotate` to cover it",
+                    outside,
+                    permitted
+                ));
+            }
+        }
+        *self.patterns.write().unwrap() = patterns;
+        Ok(())
+    }
+
+    fn mint(&self, host: &str) -> Result<(Arc<CertifiedKey>, OffsetDateTime)> {
+        let (cert_der, key_der, not_after) = certs::issue_leaf(&self.ca, &[host.to_string()])?;
+        let key = rustls::sign::any_supported_type(&PrivateKey(key_der)).context("Unsupported leaf key")?;
+        let mut chain = vec![rustls::Certificate(cert_der)];
+        chain.extend(self.chain.iter().cloned());
+        Ok((Arc::new(CertifiedKey::new(chain, key)), not_after))
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        // Without SNI there is no way to know which domain to impersonate
+        let host = hello.server_name()?.trim_end_matches('.').to_ascii_lowercase();
+        if !crate::matches_domain(&self.patterns.read().unwrap(), &host) {
+            eprintln!("   Warning: TLS handshake for unconfigured host '{}' refused", host);
+            return None;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        if let Some(key) = self.cache.lock().unwrap().get(&host, now) {
+            return Some(key);
+        }
+
+        // Not under the lock: other handshakes go on while this one signs.
+        // Two racing for the same new host both mint, and the last one is kept.
+        match self.mint(&host) {
+            Ok((key, not_after)) => {
+                println!("   Minted certificate for '{}'", host);
+                // Renewed with a tenth of its lifetime left, 9 days for a full one
+                let renew_at = now + (not_after - now) / 10 * 9;
+                self.cache.lock().unwrap().insert(host, key.clone(), renew_at);
+                Some(key)
+            }
+            Err(e) => {
+                eprintln!("   Warning: could not mint certificate for '{}': {}", host, e);
+                None
+            }
+        }
+    }
+}
+
+/// TLS config that mints leaves on demand from `ca`, and the resolver doing
+/// it, to change the patterns through later.
+pub fn sni_config(ca: CertificateAuthority, patterns: Patterns) -> (Arc<ServerConfig>, Arc<SniResolver>) {
+    let resolver = Arc::new(SniResolver::new(ca, patterns));
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    (Arc::new(config), resolver)
+}
+
+/// TLS config for a ready-made PEM chain and key.
+pub fn static_config(chain_pem: &str, key_pem: &str) -> Result<Arc<ServerConfig>> {
+    let chain: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut chain_pem.as_bytes())?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut key_pem.as_bytes())?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .context("No usable private key found")?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .context("Certificate and key do not form a valid TLS identity")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Accepts TLS connections on `listener` and hands them to `service` (usually
+/// `warp::service(routes)`), so the certificate can be chosen per connection.
+pub async fn serve_tls<S>(listener: TcpListener, config: Arc<ServerConfig>, service: S) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let acceptor = TlsAcceptor::from(config);
+
+    loop {
+        let (stream, peer) = net::accept(&listener).await;
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let tls = match acceptor.accept(stream).await {
+                Ok(tls) => tls,
+                Err(e) => {
+                    eprintln!("   TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = Http::new().serve_connection(tls, service).await {
+                eprintln!("   Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
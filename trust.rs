@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::journal::{Journal, Mutation};
+use crate::runtimes::{self, Runtime};
+use crate::STATE_DIR;
+
+const CA_CERT_FILENAME: &str = "mimikry-ca.crt";
+const NSS_DB_DIR: &str = ".pki/nssdb";
+/// Per-home directories holding Firefox/Thunderbird profiles (native, snap, flatpak).
+const MOZILLA_PROFILE_DIRS: &[&str] = &[
+    ".mozilla/firefox",
+    "snap/firefox/common/.mozilla/firefox",
+    ".var/app/org.mozilla.firefox/.mozilla/firefox",
+    ".thunderbird",
+    "snap/thunderbird/common/.thunderbird",
+    ".var/app/org.mozilla.Thunderbird/.thunderbird",
+];
+const MIN_HUMAN_UID: u32 = 1000;
+const NOBODY_UID: u32 = 65534;
+
+/// System trust store flavour. `Auto` picks one from the running distro.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrustStore {
+    Auto,
+    /// Debian/Ubuntu: /usr/local/share/ca-certificates + update-ca-certificates
+    Debian,
+    /// Fedora/RHEL: /etc/pki/ca-trust/source/anchors + update-ca-trust
+    Fedora,
+    /// Arch and other p11-kit systems: `trust anchor`
+    P11Kit,
+    /// Alpine: /usr/local/share/ca-certificates + update-ca-certificates (no --fresh)
+    Alpine,
+}
+
+/// What `install_trust` and `remove_trust` act on.
+pub struct TrustOptions {
+    pub store: TrustStore,
+    /// Every human user's NSS DBs rather than just the invoking user's
+    pub all_users: bool,
+    /// Opt-in runtime stores (Java, certifi, Node)
+    pub runtimes: Vec<Runtime>,
+}
+
+/// A store fed by dropping a PEM file into a directory and regenerating.
+struct AnchorDir {
+    dir: &'static str,
+    update: &'static [&'static str],
+    /// Regeneration after removal, which must also drop stale links
+    refresh: &'static [&'static str],
+}
+
+impl TrustStore {
+    /// Resolves `Auto` to the store of the running system.
+    pub fn detect(self) -> Result<TrustStore> {
+        if self != TrustStore::Auto {
+            return Ok(self);
+        }
+
+        if Path::new("/etc/alpine-release").exists() && has_command("update-ca-certificates") {
+            Ok(TrustStore::Alpine)
+        } else if Path::new("/etc/pki/ca-trust/source/anchors").is_dir() && has_command("update-ca-trust") {
+            Ok(TrustStore::Fedora)
+        } else if Path::new("/usr/local/share/ca-certificates").is_dir() && has_command("update-ca-certificates") {
+            Ok(TrustStore::Debian)
+        } else if has_command("trust") {
+            Ok(TrustStore::P11Kit)
+        } else {
+            Err(anyhow::anyhow!("No supported system trust store found; pick one with --trust-store"))
+        }
+    }
+
+    fn anchor_dir(self) -> Option<AnchorDir> {
+        match self {
+            TrustStore::Debian => Some(AnchorDir {
+                dir: "/usr/local/share/ca-certificates",
+                update: &["update-ca-certificates"],
+                refresh: &["update-ca-certificates", "--fresh"],
+            }),
+            TrustStore::Fedora => Some(AnchorDir {
+                dir: "/etc/pki/ca-trust/source/anchors",
+                update: &["update-ca-trust", "extract"],
+                refresh: &["update-ca-trust", "extract"],
+            }),
+            TrustStore::Alpine => Some(AnchorDir {
+                dir: "/usr/local/share/ca-certificates",
+                update: &["update-ca-certificates"],
+                refresh: &["update-ca-certificates"],
+            }),
+            TrustStore::P11Kit | TrustStore::Auto => None,
+        }
+    }
+}
+
+/// Installs the CA into the system store, the NSS DBs of the real user (or
+/// of every human user) and any requested runtime stores.
+pub fn install_trust(options: &TrustOptions, ca_pem: &str, journal: &Journal) -> Result<()> {
+    // 1. System Store
+    install_system(options.store.detect()?, ca_pem, journal)?;
+
+    // 2. NSS DBs (Chrome/VSCode, Firefox, Thunderbird)
+    for nss_db_url in nss_databases(options.all_users) {
+        install_nss(&nss_db_url, ca_pem, journal)?;
+    }
+
+    // 3. Runtimes with their own stores
+    for runtime in &options.runtimes {
+        runtimes::install(*runtime, ca_pem, journal)?;
+    }
+
+    Ok(())
+}
+
+fn install_nss(nss_db_url: &str, ca_pem: &str, journal: &Journal) -> Result<()> {
+    if nss_has_ca(nss_db_url, ca_pem) {
+        println!("   NSS DB at {} already trusts this CA", nss_db_url);
+        return Ok(());
+    }
+
+    let backup = match nss_export(nss_db_url) {
+        Some(previous) => Some(journal.store_backup("nss-mimikry-ca.crt", &previous)?),
+        None => None,
+    };
+    journal.record(Mutation::NssCert {
+        db: nss_db_url.to_string(),
+        nickname: "Mimikry CA".to_string(),
+        backup,
+        trust: "C,,".to_string(),
+    })?;
+
+    // Replace a CA from an earlier rotation, if any
+    certutil(nss_db_url)
+        .arg("-D")
+        .arg("-n")
+        .arg("Mimikry CA")
+        .arg("-d")
+        .arg(nss_db_url)
+        .output()
+        .ok();
+
+    println!("   Importing to NSS DB at: {}", nss_db_url);
+
+    // certutil -A -n "Mimikry CA" -t "C,," -a -d sql:/home/user/.pki/nssdb < ca.pem
+    // The CA goes in on stdin: no file in /tmp for other users to race.
+    let mut import = certutil(nss_db_url);
+    import
+        .arg("-A")
+        .arg("-n")
+        .arg("Mimikry CA")
+        .arg("-t")
+        .arg("C,,")
+        .arg("-a")
+        .arg("-d")
+        .arg(nss_db_url);
+    let status = output_with_stdin(import, ca_pem.as_bytes());
+
+    // It might fail if DB doesn't exist, we try our best.
+    if let Ok(out) = status {
+        if !out.status.success() {
+            eprintln!("   Warning: certutil failed: {}", String::from_utf8_lossy(&out.stderr));
+        }
+    }
+
+    Ok(())
+}
+
+fn install_system(store: TrustStore, ca_pem: &str, journal: &Journal) -> Result<()> {
+    let Some(anchors) = store.anchor_dir() else {
+        // p11-kit copies the anchor into its own store, so keep the file we
+        // fed it: removal needs the same certificate.
+        let anchor = journal.store_backup("p11-kit-anchor.crt", ca_pem)?;
+        journal.record(Mutation::TrustAnchor { cert: anchor.clone() })?;
+
+        println!("   Adding anchor via p11-kit...");
+        return run(&["trust", "anchor", "--store"], Some(&anchor));
+    };
+
+    let sys_cert_path = Path::new(anchors.dir).join(CA_CERT_FILENAME);
+    if fs::read_to_string(&sys_cert_path).map(|c| c == ca_pem).unwrap_or(false) {
+        println!("   System CA store already trusts this CA");
+        return Ok(());
+    }
+
+    journal.record_file(&sys_cert_path, Some(anchors.refresh.iter().map(|s| s.to_string()).collect()))?;
+    let mut file = File::create(&sys_cert_path)?;
+    file.write_all(ca_pem.as_bytes())?;
+
+    println!("   Updating system CA store...");
+    run(anchors.update, None)
+}
+
+/// Drops the CA from the system and NSS stores. `ca_pem` is the CA being
+/// removed, which p11-kit needs to find its anchor.
+pub fn remove_trust(options: &TrustOptions, ca_pem: Option<&str>) -> Result<()> {
+    // 1. Remove from System
+    let store = options.store.detect()?;
+    match store.anchor_dir() {
+        Some(anchors) => {
+            let sys_cert_path = Path::new(anchors.dir).join(CA_CERT_FILENAME);
+            if sys_cert_path.exists() {
+                fs::remove_file(sys_cert_path)?;
+            }
+            // We verify strict "fresh" removal
+            run(anchors.refresh, None)?;
+        }
+        None => {
+            if let Some(ca_pem) = ca_pem {
+                let mut anchor = tempfile::NamedTempFile::new_in(STATE_DIR)?;
+                anchor.write_all(ca_pem.as_bytes())?;
+                // Fails if it was never anchored, which is fine
+                run(&["trust", "anchor", "--remove"], Some(anchor.path())).ok();
+            }
+        }
+    }
+
+    // 2. Remove from NSS DBs
+    for nss_db_url in nss_databases(options.all_users) {
+        certutil(&nss_db_url)
+            .arg("-D")
+            .arg("-n")
+            .arg("Mimikry CA")
+            .arg("-d")
+            .arg(nss_db_url)
+            .output()
+            .ok(); // Ignore errors if cert didn't exist
+    }
+
+    // 3. Remove from runtimes, whether or not they were requested this time
+    runtimes::remove_all();
+
+    Ok(())
+}
+
+/// Undoes `trust anchor --store` for the anchor recorded in the journal.
+pub fn remove_anchor(cert: &Path) -> Result<()> {
+    run(&["trust", "anchor", "--remove"], Some(cert))
+}
+
+fn run(command: &[&str], file: Option<&Path>) -> Result<()> {
+    let mut cmd = Command::new(command[0]);
+    cmd.args(&command[1..]);
+    if let Some(file) = file {
+        cmd.arg(file);
+    }
+
+    let out = cmd.output().with_context(|| format!("Failed to run {}", command[0]))?;
+    if !out.status.success() {
+        return Err(anyhow::anyhow!(
+            "`{}` failed: {}",
+            command.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn has_command(name: &str) -> bool {
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+/// certutil, run as the owner of the NSS DB so that files NSS creates or
+/// rewrites there don't end up owned by root.
+pub fn certutil(nss_db_url: &str) -> Command {
+    nss_tool("certutil", nss_db_url)
+}
+
+/// `program` (one of the NSS tools), run as the owner of the NSS DB.
+pub fn nss_tool(program: &str, nss_db_url: &str) -> Command {
+    let mut cmd = Command::new(program);
+    let dir = Path::new(nss_db_url.strip_prefix("sql:").unwrap_or(nss_db_url));
+    // A DB that doesn't exist yet belongs to whoever owns the nearest parent
+    if let Some(meta) = dir.ancestors().find_map(|p| fs::metadata(p).ok()) {
+        cmd.uid(meta.uid()).gid(meta.gid());
+    }
+    cmd
+}
+
+/// Runs `cmd` feeding `input` on stdin.
+pub fn output_with_stdin(mut cmd: Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+    child.wait_with_output()
+}
+
+/// `sql:` URLs of the NSS DBs to trust the CA in: the shared ~/.pki/nssdb
+/// plus every Firefox/Thunderbird profile, including snap and flatpak ones.
+fn nss_databases(all_users: bool) -> Vec<String> {
+    let homes = if all_users {
+        human_user_homes()
+    } else {
+        crate::get_real_user_home().into_iter().collect()
+    };
+
+    let mut dbs = Vec::new();
+    for home in homes {
+        // Chromium based apps create this one lazily, so it may not exist yet
+        dbs.push(home.join(NSS_DB_DIR));
+
+        for profiles in MOZILLA_PROFILE_DIRS {
+            let Ok(entries) = fs::read_dir(home.join(profiles)) else { continue };
+            dbs.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.join("cert9.db").is_file()));
+        }
+    }
+
+    dbs.into_iter().map(|db| format!("sql:{}", db.to_string_lossy())).collect()
+}
+
+/// Homes of regular login users from /etc/passwd.
+fn human_user_homes() -> Vec<PathBuf> {
+    let Ok(passwd) = fs::read_to_string("/etc/passwd") else {
+        return Vec::new();
+    };
+
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let (uid, home, shell) = (fields.get(2)?.parse::<u32>().ok()?, fields.get(5)?, fields.get(6)?);
+            let is_human = (MIN_HUMAN_UID..NOBODY_UID).contains(&uid)
+                && !shell.ends_with("nologin")
+                && !shell.ends_with("false");
+            is_human.then(|| PathBuf::from(home))
+        })
+        .filter(|home| home.is_dir())
+        .collect()
+}
+
+/// True if the NSS DB already holds exactly this CA under our nickname.
+fn nss_has_ca(nss_db_url: &str, ca_pem: &str) -> bool {
+    let normalize = |pem: &str| pem.split_whitespace().collect::<String>();
+    nss_export(nss_db_url).map(|pem| normalize(&pem) == normalize(ca_pem)).unwrap_or(false)
+}
+
+/// PEM of the certificate currently under our nickname, if any.
+fn nss_export(nss_db_url: &str) -> Option<String> {
+    let out = certutil(nss_db_url)
+        .arg("-L")
+        .arg("-a")
+        .arg("-n")
+        .arg("Mimikry CA")
+        .arg("-d")
+        .arg(nss_db_url)
+        .output()
+        .ok()?;
+
+    if out.status.success() {
+        Some(String::from_utf8_lossy(&out.stdout).into_owned())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::Reply;
+
+use crate::index::{self, ArtifactIndex};
+
+const DEFAULT_UPDATE_HOST: &str = "update.code.visualstudio.com";
+
+/// `GET /api/update/{platform}/{quality}/{commit}`, as served by vscgallery.
+///
+/// Reads `installers/<platform>/<quality>/latest.json` written by vscsync and
+/// answers 204 when the client already runs that commit.
+pub async fn handle_update(
+    artifacts: &Path,
+    index: &ArtifactIndex,
+    host: Option<&str>,
+    platform: &str,
+    quality: &str,
+    commit: &str,
+) -> Response {
+    if !is_safe_segment(platform) || !is_safe_segment(quality) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let update_dir = artifacts.join("installers").join(platform).join(quality);
+    if !update_dir.is_dir() {
+        eprintln!("   Warning: update build directory does not exist at {:?}. Check sync or sync configuration.", update_dir);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let Some(mut latest) = load_json(&update_dir.join("latest.json")) else {
+        eprintln!("   Warning: unable to load latest.json for platform {} and quality {}", platform, quality);
+        return status_text("Unable to load latest.json", StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    if latest["version"].as_str() == Some(commit) {
+        println!("   Client {} ({}) is current. No update available.", platform, quality);
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    let name = latest["name"].as_str().unwrap_or_default().to_string();
+    let Some(payload) = first_payload(&update_dir, &name) else {
+        eprintln!("   Warning: unable to find update payload {:?}/vscode-{}.*", update_dir, name);
+        return status_text("Unable to find update payload", StatusCode::NOT_FOUND);
+    };
+
+    let expected = latest["sha256hash"].as_str().unwrap_or_default().to_string();
+    match payload_hash(index, &payload).await {
+        Ok(actual) if actual == expected => {}
+        Ok(_) => {
+            eprintln!("   Warning: update payload hash mismatch {:?}", payload);
+            return status_text("Update payload hash mismatch", StatusCode::FORBIDDEN);
+        }
+        Err(e) => {
+            eprintln!("   Warning: could not hash update payload {:?}: {}", payload, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let Ok(relative) = payload.strip_prefix(artifacts) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    latest["url"] = Value::String(format!(
+        "https://{}/artifacts/{}",
+        host.unwrap_or(DEFAULT_UPDATE_HOST),
+        relative.to_string_lossy()
+    ));
+
+    println!("   Client {} ({}). Providing update {:?}", platform, quality, payload);
+    warp::reply::json(&latest).into_response()
+}
+
+/// Rejects path segments that could escape the installers directory.
+fn is_safe_segment(segment: &str) -> bool {
+    !segment.is_empty() && !segment.starts_with('.') && !segment.contains(['/', '\\'])
+}
+
+fn status_text(message: &'static str, status: StatusCode) -> Response {
+    warp::reply::with_status(message, status).into_response()
+}
+
+pub fn load_json(path: &Path) -> Option<Value> {
+    let raw = fs::read(path).ok()?;
+    // vscsync may write a UTF-8 BOM
+    let raw = raw.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&raw);
+    match serde_json::from_slice(raw) {
+        Ok(Value::Null) => None,
+        Ok(value) => Some(value),
+        Err(e) => {
+            eprintln!("   Warning: invalid json in {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Equivalent of `Utility.first_file(update_dir, 'vscode-{name}.*')`.
+fn first_payload(update_dir: &Path, name: &str) -> Option<PathBuf> {
+    let prefix = format!("vscode-{}.", name);
+    let mut matches: Vec<PathBuf> = fs::read_dir(update_dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+        .map(|e| e.path())
+        .collect();
+    matches.sort();
+    matches.into_iter().next()
+}
+
+/// Uses the index's cached hash when it is current, otherwise hashes the file.
+async fn payload_hash(index: &ArtifactIndex, payload: &Path) -> Result<String> {
+    if let Some(entry) = index.current_entry(payload) {
+        return Ok(entry.sha256);
+    }
+
+    let payload = payload.to_path_buf();
+    Ok(tokio::task::spawn_blocking(move || index::hash_file(&payload)).await??)
+}